@@ -0,0 +1,102 @@
+//! `Kbucket::closest` and `Kbucket::closest_iter`, as run for every incoming
+//! find_node and get_peers, against the algorithm `closest` used before:
+//! collecting whole buckets and sorting all of them.
+//!
+//! Run with `cargo bench --features bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mainline::kbucket::{Contact, Distance, Kbucket};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node([u8; 20]);
+
+impl Contact for Node {
+    type Id = [u8; 20];
+
+    fn id(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+fn table(rng: &mut StdRng, nodes_per_kbucket: usize, contacts: usize) -> Kbucket<[u8; 20], Node> {
+    let mut table = Kbucket::new(rng.gen(), Some(nodes_per_kbucket), None);
+    for _ in 0..contacts {
+        table.add(Node(rng.gen()));
+    }
+    table
+}
+
+/// The `n` closest the way `Kbucket::closest` found them before: visit the
+/// buckets nearest to `target` first and take all of their contacts until
+/// there are `n`, then sort everything that was taken.
+///
+/// The tree is private, so the buckets are put in the order the tree walk
+/// visited them by the distance of their prefix, padded with zeros, to
+/// `target`. Prefixes of different buckets differ in a bit they both have,
+/// so the padding never decides the order.
+fn sort_buckets(table: &Kbucket<[u8; 20], Node>, target: [u8; 20], n: usize) -> Vec<&Node> {
+    let mut buckets: Vec<_> = table
+        .buckets()
+        .map(|bucket| {
+            let mut start = [0; 20];
+            bucket.prefix.lowest_id(&mut start);
+            (Distance::between(&start, &target), bucket.contacts)
+        })
+        .collect();
+    buckets.sort_unstable_by_key(|(distance, _)| *distance);
+
+    let mut contacts = Vec::with_capacity(n);
+    for (_, bucket) in buckets {
+        if contacts.len() >= n {
+            break;
+        }
+        contacts.extend(bucket);
+    }
+    contacts.sort_by_cached_key(|contact| contact.distance(&target));
+    contacts.truncate(n);
+    contacts
+}
+
+fn bench_closest(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut group = c.benchmark_group("closest");
+    for nodes_per_kbucket in [20, 200] {
+        let table = table(&mut rng, nodes_per_kbucket, 100_000);
+        let targets: Vec<[u8; 20]> = (0..64).map(|_| rng.gen()).collect();
+        let size = format!("{} contacts", table.len());
+
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::new("closest", &size), &table, |b, table| {
+            b.iter(|| {
+                i = (i + 1) % targets.len();
+                table.closest(targets[i], Some(20)).len()
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("closest_iter", &size),
+            &table,
+            |b, table| {
+                b.iter(|| {
+                    i = (i + 1) % targets.len();
+                    table.closest_iter(targets[i]).take(20).count()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("sort_buckets", &size),
+            &table,
+            |b, table| {
+                b.iter(|| {
+                    i = (i + 1) % targets.len();
+                    sort_buckets(table, targets[i], 20).len()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_closest);
+criterion_main!(benches);
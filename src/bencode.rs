@@ -0,0 +1,614 @@
+//! Bencode codec, as used on the wire by KRPC (see BEP 3).
+//!
+//! The encoder writes directly into a buffer, so messages can be produced
+//! without building an intermediate [`Value`] tree. The decoder is strict: it
+//! only accepts the canonical encoding of a value and rejects anything else.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Largest input accepted by [`decode`], a full UDP datagram.
+pub const MAX_SIZE: usize = 65_507;
+
+/// Deepest nesting of lists and dictionaries accepted by [`decode`].
+pub const MAX_DEPTH: usize = 32;
+
+/// A decoded bencode value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.as_dict().and_then(|d| d.get(key))
+    }
+
+    /// Returns the canonical encoding of this value.
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.value(self);
+        encoder.finish()
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+/// Errors produced while decoding. Positions are byte offsets into the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The input ended in the middle of a value.
+    UnexpectedEof,
+    /// A byte that can not appear at this position.
+    UnexpectedByte { pos: usize, byte: u8 },
+    /// An integer without digits, e.g. `ie` or `i-e`.
+    EmptyInteger { pos: usize },
+    /// An integer or length with leading zeros, or a negative zero.
+    NonCanonicalNumber { pos: usize },
+    /// An integer that does not fit into an `i64`.
+    IntegerOverflow { pos: usize },
+    /// A byte string whose length runs past the end of the input.
+    LengthOutOfBounds { pos: usize, len: usize },
+    /// A dictionary key that is not a byte string.
+    NonStringKey { pos: usize },
+    /// A dictionary key that does not sort after the previous one.
+    UnsortedKey { pos: usize },
+    /// A dictionary key that appears more than once.
+    DuplicateKey { pos: usize },
+    /// Lists and dictionaries are nested deeper than allowed.
+    TooDeep { pos: usize },
+    /// The input is larger than allowed.
+    TooLarge { size: usize, max: usize },
+    /// Extra bytes after the end of the top level value.
+    TrailingData { pos: usize },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "unexpected end of input"),
+            Error::UnexpectedByte { pos, byte } => {
+                write!(f, "unexpected byte 0x{byte:02x} at {pos}")
+            }
+            Error::EmptyInteger { pos } => write!(f, "integer without digits at {pos}"),
+            Error::NonCanonicalNumber { pos } => write!(f, "non canonical number at {pos}"),
+            Error::IntegerOverflow { pos } => write!(f, "integer overflow at {pos}"),
+            Error::LengthOutOfBounds { pos, len } => {
+                write!(f, "byte string of length {len} at {pos} exceeds input")
+            }
+            Error::NonStringKey { pos } => write!(f, "dictionary key at {pos} is not a string"),
+            Error::UnsortedKey { pos } => write!(f, "dictionary key at {pos} is out of order"),
+            Error::DuplicateKey { pos } => write!(f, "duplicate dictionary key at {pos}"),
+            Error::TooDeep { pos } => write!(f, "nesting too deep at {pos}"),
+            Error::TooLarge { size, max } => {
+                write!(f, "input of {size} bytes exceeds maximum of {max}")
+            }
+            Error::TrailingData { pos } => write!(f, "trailing data at {pos}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Limits enforced by the decoder.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_size: usize,
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_size: MAX_SIZE,
+            max_depth: MAX_DEPTH,
+        }
+    }
+}
+
+/// Decodes exactly one value from `buf`, using the default [`Limits`].
+pub fn decode(buf: &[u8]) -> Result<Value, Error> {
+    decode_with_limits(buf, Limits::default())
+}
+
+/// Decodes exactly one value from `buf`.
+pub fn decode_with_limits(buf: &[u8], limits: Limits) -> Result<Value, Error> {
+    if buf.len() > limits.max_size {
+        return Err(Error::TooLarge {
+            size: buf.len(),
+            max: limits.max_size,
+        });
+    }
+
+    let mut decoder = Decoder {
+        buf,
+        pos: 0,
+        max_depth: limits.max_depth,
+    };
+    let value = decoder.value(0)?;
+    if decoder.pos != buf.len() {
+        return Err(Error::TrailingData { pos: decoder.pos });
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    max_depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, Error> {
+        self.buf.get(self.pos).copied().ok_or(Error::UnexpectedEof)
+    }
+
+    fn next(&mut self) -> Result<u8, Error> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let i = self.number(b'e')?;
+                Ok(Value::Integer(i))
+            }
+            b'0'..=b'9' => self.bytes().map(|b| Value::Bytes(b.to_vec())),
+            b'l' => {
+                if depth >= self.max_depth {
+                    return Err(Error::TooDeep { pos: self.pos });
+                }
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                if depth >= self.max_depth {
+                    return Err(Error::TooDeep { pos: self.pos });
+                }
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                let mut last_key: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(Error::NonStringKey { pos: key_pos });
+                    }
+                    let key = self.bytes()?;
+                    if let Some(last_key) = last_key {
+                        match last_key.cmp(key) {
+                            std::cmp::Ordering::Less => {}
+                            std::cmp::Ordering::Equal => {
+                                return Err(Error::DuplicateKey { pos: key_pos })
+                            }
+                            std::cmp::Ordering::Greater => {
+                                return Err(Error::UnsortedKey { pos: key_pos })
+                            }
+                        }
+                    }
+                    last_key = Some(key);
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key.to_vec(), value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            byte => Err(Error::UnexpectedByte {
+                pos: self.pos,
+                byte,
+            }),
+        }
+    }
+
+    /// Reads a `<length>:<bytes>` string.
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let pos = self.pos;
+        // a leading '-' is only allowed for integers, so this is never negative
+        let len = self.number(b':')? as usize;
+        let start = self.pos;
+        let end = start
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or(Error::LengthOutOfBounds { pos, len })?;
+        self.pos = end;
+        Ok(&self.buf[start..end])
+    }
+
+    /// Reads a canonical decimal number up to and including `terminator`.
+    fn number(&mut self, terminator: u8) -> Result<i64, Error> {
+        let start = self.pos;
+        let negative = terminator == b'e' && self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+
+        let digits_start = self.pos;
+        let mut value: i64 = 0;
+        loop {
+            let byte = self.next()?;
+            match byte {
+                b'0'..=b'9' => {
+                    let digit = i64::from(byte - b'0');
+                    value = value
+                        .checked_mul(10)
+                        .and_then(|v| {
+                            if negative {
+                                v.checked_sub(digit)
+                            } else {
+                                v.checked_add(digit)
+                            }
+                        })
+                        .ok_or(Error::IntegerOverflow { pos: start })?;
+                }
+                b if b == terminator => break,
+                byte => {
+                    return Err(Error::UnexpectedByte {
+                        pos: self.pos - 1,
+                        byte,
+                    })
+                }
+            }
+        }
+
+        let digits = &self.buf[digits_start..self.pos - 1];
+        if digits.is_empty() {
+            return Err(Error::EmptyInteger { pos: start });
+        }
+        if (digits.len() > 1 && digits[0] == b'0') || (negative && value == 0) {
+            return Err(Error::NonCanonicalNumber { pos: start });
+        }
+        Ok(value)
+    }
+}
+
+/// Streaming bencode encoder.
+///
+/// Values are written to the output as soon as they are added. Dictionary
+/// keys must be added in sorted order, see [`DictEncoder`].
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn integer(&mut self, i: i64) {
+        self.buf.push(b'i');
+        self.buf.extend_from_slice(i.to_string().as_bytes());
+        self.buf.push(b'e');
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b.len().to_string().as_bytes());
+        self.buf.push(b':');
+        self.buf.extend_from_slice(b);
+    }
+
+    /// Writes a list, whose items are written by `f`.
+    pub fn list(&mut self, f: impl FnOnce(&mut Encoder)) {
+        self.buf.push(b'l');
+        f(self);
+        self.buf.push(b'e');
+    }
+
+    /// Writes a dictionary, whose entries are written by `f`.
+    pub fn dict(&mut self, f: impl FnOnce(&mut DictEncoder<'_>)) {
+        self.buf.push(b'd');
+        let mut dict = DictEncoder {
+            encoder: self,
+            last_key: None,
+        };
+        f(&mut dict);
+        self.buf.push(b'e');
+    }
+
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::Integer(i) => self.integer(*i),
+            Value::Bytes(b) => self.bytes(b),
+            Value::List(l) => self.list(|e| {
+                for v in l {
+                    e.value(v);
+                }
+            }),
+            Value::Dict(d) => self.dict(|e| {
+                for (k, v) in d {
+                    e.value(k, v);
+                }
+            }),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Writes the entries of a dictionary.
+///
+/// # Panics
+///
+/// Every method panics if `key` does not sort strictly after the previously
+/// written key, as the result would not be canonical bencode.
+#[derive(Debug)]
+pub struct DictEncoder<'a> {
+    encoder: &'a mut Encoder,
+    /// Location of the previous key in the output buffer.
+    last_key: Option<Range<usize>>,
+}
+
+impl<'a> DictEncoder<'a> {
+    /// Writes `key` and returns the encoder for its value, which must be
+    /// written exactly once.
+    pub fn entry(&mut self, key: &[u8]) -> &mut Encoder {
+        if let Some(last_key) = self.last_key.clone() {
+            assert!(
+                &self.encoder.buf[last_key] < key,
+                "dictionary keys must be written in sorted order"
+            );
+        }
+        self.encoder.bytes(key);
+        let end = self.encoder.buf.len();
+        self.last_key = Some(end - key.len()..end);
+        self.encoder
+    }
+
+    pub fn integer(&mut self, key: &[u8], i: i64) {
+        self.entry(key).integer(i);
+    }
+
+    pub fn bytes(&mut self, key: &[u8], b: &[u8]) {
+        self.entry(key).bytes(b);
+    }
+
+    pub fn list(&mut self, key: &[u8], f: impl FnOnce(&mut Encoder)) {
+        self.entry(key).list(f);
+    }
+
+    pub fn dict(&mut self, key: &[u8], f: impl FnOnce(&mut DictEncoder<'_>)) {
+        self.entry(key).dict(f);
+    }
+
+    pub fn value(&mut self, key: &[u8], value: &Value) {
+        self.entry(key).value(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(entries: &[(&[u8], Value)]) -> Value {
+        Value::Dict(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_decode_values() {
+        assert_eq!(decode(b"i0e").unwrap(), Value::Integer(0));
+        assert_eq!(decode(b"i-42e").unwrap(), Value::Integer(-42));
+        assert_eq!(
+            decode(b"i9223372036854775807e").unwrap(),
+            Value::Integer(i64::MAX)
+        );
+        assert_eq!(
+            decode(b"i-9223372036854775808e").unwrap(),
+            Value::Integer(i64::MIN)
+        );
+        assert_eq!(decode(b"0:").unwrap(), Value::Bytes(vec![]));
+        assert_eq!(decode(b"4:spam").unwrap(), Value::from(&b"spam"[..]));
+        assert_eq!(
+            decode(b"l4:spami3ee").unwrap(),
+            Value::List(vec![Value::from(&b"spam"[..]), Value::Integer(3)])
+        );
+        assert_eq!(
+            decode(b"d3:cow3:moo4:spaml1:a1:bee").unwrap(),
+            dict(&[
+                (b"cow", Value::from(&b"moo"[..])),
+                (
+                    b"spam",
+                    Value::List(vec![Value::from(&b"a"[..]), Value::from(&b"b"[..])])
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let inputs: &[&[u8]] = &[
+            b"i0e",
+            b"i-1e",
+            b"le",
+            b"de",
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+            b"d1:rd2:id20:mnopqrstuvwxyz1234565:nodes0:e1:t2:aa1:y1:re",
+            b"lli1eli2eeed1:xleee",
+        ];
+        for input in inputs {
+            let value = decode(input).unwrap();
+            assert_eq!(&value.encode()[..], *input);
+        }
+    }
+
+    #[test]
+    fn test_reject_malformed() {
+        assert_eq!(decode(b""), Err(Error::UnexpectedEof));
+        assert_eq!(decode(b"i12"), Err(Error::UnexpectedEof));
+        assert_eq!(decode(b"l"), Err(Error::UnexpectedEof));
+        assert_eq!(decode(b"d1:a"), Err(Error::UnexpectedEof));
+        assert_eq!(
+            decode(b"x"),
+            Err(Error::UnexpectedByte { pos: 0, byte: b'x' })
+        );
+        assert_eq!(
+            decode(b"i1x2e"),
+            Err(Error::UnexpectedByte { pos: 2, byte: b'x' })
+        );
+        assert_eq!(
+            decode(b"i+1e"),
+            Err(Error::UnexpectedByte { pos: 1, byte: b'+' })
+        );
+        assert_eq!(
+            decode(b"-1:a"),
+            Err(Error::UnexpectedByte { pos: 0, byte: b'-' })
+        );
+        assert_eq!(decode(b"ie"), Err(Error::EmptyInteger { pos: 1 }));
+        assert_eq!(decode(b"i-e"), Err(Error::EmptyInteger { pos: 1 }));
+        assert_eq!(
+            decode(b"5:abc"),
+            Err(Error::LengthOutOfBounds { pos: 0, len: 5 })
+        );
+        assert_eq!(decode(b"di1ei2ee"), Err(Error::NonStringKey { pos: 1 }));
+        assert_eq!(decode(b"i1ei2e"), Err(Error::TrailingData { pos: 3 }));
+    }
+
+    #[test]
+    fn test_reject_non_canonical() {
+        assert_eq!(decode(b"i00e"), Err(Error::NonCanonicalNumber { pos: 1 }));
+        assert_eq!(decode(b"i01e"), Err(Error::NonCanonicalNumber { pos: 1 }));
+        assert_eq!(decode(b"i-0e"), Err(Error::NonCanonicalNumber { pos: 1 }));
+        assert_eq!(decode(b"i-01e"), Err(Error::NonCanonicalNumber { pos: 1 }));
+        assert_eq!(
+            decode(b"04:spam"),
+            Err(Error::NonCanonicalNumber { pos: 0 })
+        );
+        assert_eq!(decode(b"d1:b0:1:a0:e"), Err(Error::UnsortedKey { pos: 6 }));
+        assert_eq!(decode(b"d1:a0:1:a0:e"), Err(Error::DuplicateKey { pos: 6 }));
+    }
+
+    #[test]
+    fn test_reject_oversized() {
+        assert_eq!(
+            decode(b"i9223372036854775808e"),
+            Err(Error::IntegerOverflow { pos: 1 })
+        );
+        assert_eq!(
+            decode(b"i-9223372036854775809e"),
+            Err(Error::IntegerOverflow { pos: 1 })
+        );
+        assert_eq!(
+            decode(b"99999999999999999999:"),
+            Err(Error::IntegerOverflow { pos: 0 })
+        );
+
+        let deep = [&[b'l'; MAX_DEPTH + 1][..], &[b'e'; MAX_DEPTH + 1][..]].concat();
+        assert_eq!(decode(&deep), Err(Error::TooDeep { pos: MAX_DEPTH }));
+        let ok = &deep[1..deep.len() - 1];
+        assert!(decode(ok).is_ok());
+
+        let big = vec![b'0'; MAX_SIZE + 1];
+        assert_eq!(
+            decode(&big),
+            Err(Error::TooLarge {
+                size: MAX_SIZE + 1,
+                max: MAX_SIZE
+            })
+        );
+
+        let limits = Limits {
+            max_size: 3,
+            max_depth: 1,
+        };
+        assert!(decode_with_limits(b"le", limits).is_ok());
+        assert_eq!(
+            decode_with_limits(b"llee", limits),
+            Err(Error::TooLarge { size: 4, max: 3 })
+        );
+    }
+
+    #[test]
+    fn test_streaming_encoder() {
+        let mut encoder = Encoder::new();
+        encoder.dict(|d| {
+            d.dict(b"a", |a| {
+                a.bytes(b"id", b"abcdefghij0123456789");
+                a.list(b"list", |l| {
+                    l.integer(-3);
+                    l.bytes(b"");
+                });
+            });
+            d.bytes(b"q", b"ping");
+            d.integer(b"z", 7);
+        });
+        let buf = encoder.finish();
+        assert_eq!(
+            &buf[..],
+            &b"d1:ad2:id20:abcdefghij01234567894:listli-3e0:ee1:q4:ping1:zi7ee"[..]
+        );
+        assert_eq!(decode(&buf).unwrap().encode(), buf);
+    }
+
+    #[test]
+    #[should_panic(expected = "sorted order")]
+    fn test_encoder_rejects_unsorted_keys() {
+        let mut encoder = Encoder::new();
+        encoder.dict(|d| {
+            d.integer(b"y", 1);
+            d.integer(b"t", 2);
+        });
+    }
+}
@@ -0,0 +1,109 @@
+//! Hash functions of the DHT, and the ids they produce.
+//!
+//! Node ids, info hashes and tokens all have the output length of the hash
+//! function. BitTorrent uses 160-bit SHA-1, other networks can use SHA-256.
+
+use std::fmt::Debug;
+
+use sha1::Digest;
+
+/// A node id or key: the fixed size output of a [`Hash`].
+pub trait Id:
+    Copy
+    + Eq
+    + Ord
+    + std::hash::Hash
+    + Default
+    + Debug
+    + AsRef<[u8]>
+    + AsMut<[u8]>
+    + Send
+    + Sync
+    + Unpin
+    + 'static
+{
+    /// Length in bytes.
+    const LEN: usize;
+
+    /// Copies an id of exactly `LEN` bytes.
+    fn from_slice(bytes: &[u8]) -> Option<Self>;
+}
+
+impl<const N: usize> Id for [u8; N]
+where
+    [u8; N]: Default,
+{
+    const LEN: usize = N;
+
+    fn from_slice(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+/// The hash function of a DHT, which also defines the length of its ids.
+pub trait Hash: Send + Sync + 'static {
+    type Id: Id;
+
+    /// Bootstrap nodes used by default, see [`Opts::bootstrap`](crate::Opts::bootstrap).
+    /// Only nodes of the same network can answer, so none by default.
+    const DEFAULT_BOOTSTRAP: &'static [&'static str] = &[];
+
+    /// Hashes the concatenation of `parts`.
+    fn digest(parts: &[&[u8]]) -> Self::Id;
+}
+
+/// SHA-1 with 20 byte ids, as used by BitTorrent (BEP 5).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha1;
+
+impl Hash for Sha1 {
+    type Id = [u8; 20];
+
+    const DEFAULT_BOOTSTRAP: &'static [&'static str] = &[
+        "udp://router.bittorrent.com:6881",
+        "udp://router.utorrent.com:6881",
+        "udp://dht.transmissionbt.com:6881",
+    ];
+
+    fn digest(parts: &[&[u8]]) -> Self::Id {
+        let mut hasher = sha1::Sha1::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// SHA-256 with 32 byte ids, for private networks without bootstrap nodes
+/// by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256;
+
+impl Hash for Sha256 {
+    type Id = [u8; 32];
+
+    fn digest(parts: &[&[u8]]) -> Self::Id {
+        let mut hasher = sha2::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            Sha1::digest(&[b"ab", b"c"]),
+            *b"\xa9\x99\x3e\x36\x47\x06\x81\x6a\xba\x3e\x25\x71\x78\x50\xc2\x6c\x9c\xd0\xd8\x9d"
+        );
+        assert_eq!(
+            Sha256::digest(&[b"abc"]),
+            *b"\xba\x78\x16\xbf\x8f\x01\xcf\xea\x41\x41\x40\xde\x5d\xae\x22\x23\xb0\x03\x61\xa3\x96\x17\x7a\x9c\xb4\x10\xff\x61\xf2\x00\x15\xad"
+        );
+    }
+}
@@ -1,45 +1,197 @@
 //! Based on https://github.com/tristanls/k-bucket/blob/master/index.js
 
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+use rand::RngCore;
+use tokio::sync::broadcast;
+
 #[derive(Debug)]
-pub struct Kbucket<I: AsRef<[u8]>, V: Contact<Id = I>> {
+pub struct Kbucket<I: AsRef<[u8]>, V: Contact<Id = I>, A = Latest> {
     node_id: I,
     nodes_per_kbucket: usize,
     nodes_to_ping: usize,
     root: Node<V>,
+    /// Created by the first `subscribe`, tables without subscribers don't
+    /// pay for the event buffer
+    events: Option<broadcast::Sender<Event<V>>>,
+    arbiter: A,
 }
 
-pub trait Contact: PartialEq + std::fmt::Debug {
-    type Id: AsRef<[u8]>;
+/// Events buffered for each subscriber. Subscribers that fall further behind
+/// miss the oldest ones.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// Changes to the contacts of a routing table, see `Kbucket::subscribe`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<V> {
+    /// A new contact was added.
+    Added(V),
+    /// A contact was removed.
+    Removed(V),
+    /// A known contact was added again, and moved to the end of its bucket.
+    /// `new` is the version that was kept, which is `old` unless the
+    /// `Arbiter` of the table preferred the new one.
+    Updated { old: V, new: V },
+    /// `new` did not fit in a full bucket that may not be split. `old` are the
+    /// least recently seen questionable contacts of the bucket, up to
+    /// `nodes_to_ping`. If one of them does not respond to a ping, remove it:
+    /// `new` waits in the replacement cache of the bucket and is promoted in
+    /// its place. Not sent if all contacts of the bucket are good.
+    Ping { old: Vec<V>, new: V },
+}
+
+impl<V> Event<V> {
+    /// Converts the contacts of the event with `f`.
+    pub fn map<W>(self, mut f: impl FnMut(V) -> W) -> Event<W> {
+        match self {
+            Event::Added(contact) => Event::Added(f(contact)),
+            Event::Removed(contact) => Event::Removed(f(contact)),
+            Event::Updated { old, new } => Event::Updated {
+                old: f(old),
+                new: f(new),
+            },
+            Event::Ping { old, new } => Event::Ping {
+                old: old.into_iter().map(&mut f).collect(),
+                new: f(new),
+            },
+        }
+    }
+}
+
+/// Liveness of a contact, as defined in BEP5.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Known to be alive, never pinged to make room for a new contact.
+    Good,
+    /// Not known to be alive, pinged before it is replaced.
+    Questionable,
+    /// Known to be dead, replaced by new contacts right away.
+    Bad,
+}
+
+pub trait Contact: PartialEq + Clone + std::fmt::Debug {
+    type Id: AsRef<[u8]> + AsMut<[u8]> + Clone;
     fn id(&self) -> &Self::Id;
 
-    fn distance(&self, other_id: &Self::Id) -> usize {
-        xor_distance(self.id().as_ref(), other_id.as_ref())
+    fn distance(&self, other_id: &Self::Id) -> Distance<Self::Id> {
+        Distance::between(self.id(), other_id)
+    }
+
+    /// Contacts that do not track their liveness are always questionable.
+    fn status(&self) -> Status {
+        Status::Questionable
+    }
+
+    fn is_bad(&self) -> bool {
+        self.status() == Status::Bad
     }
+}
+
+/// Decides which version of a contact to keep when a contact with a known id
+/// is added again, see [`Kbucket::with_arbiter`].
+pub trait Arbiter<V> {
+    /// Returns `true` if `candidate` should replace `incumbent`
+    fn should_replace(&self, incumbent: &V, candidate: &V) -> bool;
+}
 
-    /// Returns `true` if `other` should replace `self`
-    fn should_replace<'a>(&'a self, other: &'a Self) -> bool {
-        // TODO:
+impl<V, F: Fn(&V, &V) -> bool> Arbiter<V> for F {
+    fn should_replace(&self, incumbent: &V, candidate: &V) -> bool {
+        self(incumbent, candidate)
+    }
+}
+
+/// The default [`Arbiter`]: the most recently added version always wins.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Latest;
+
+impl<V> Arbiter<V> for Latest {
+    fn should_replace(&self, _incumbent: &V, _candidate: &V) -> bool {
         true
     }
 }
 
-fn xor_distance(first_id: &[u8], second_id: &[u8]) -> usize {
-    let mut distance: usize = 0;
+/// XOR distance between two ids, ordered as a big endian unsigned integer
+/// over the full width of the ids.
+///
+/// The distance is an id itself, e.g. a `[u8; 20]` for SHA-1 ids, so it is
+/// computed and compared without allocating.
+#[derive(Clone, Copy)]
+pub struct Distance<I>(I);
+
+impl<I: AsRef<[u8]> + AsMut<[u8]> + Clone> Distance<I> {
+    pub fn between(first_id: &I, second_id: &I) -> Self {
+        let mut distance = first_id.clone();
+        for (byte, other) in distance.as_mut().iter_mut().zip(second_id.as_ref()) {
+            *byte ^= other;
+        }
+        Distance(distance)
+    }
+}
 
-    let min = first_id.len().min(second_id.len());
-    let max = first_id.len().max(second_id.len());
+impl<I: AsRef<[u8]>> Distance<I> {
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
 
-    for (a, b) in first_id.iter().zip(second_id.iter()) {
-        distance = distance
-            .wrapping_mul(256)
-            .wrapping_add((*a as usize) ^ (*b as usize));
+    /// Number of leading zero bits, i.e. the length of the prefix both ids
+    /// have in common.
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for byte in self.as_bytes() {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        zeros
     }
 
-    for _ in min..max {
-        distance = distance.wrapping_mul(256).wrapping_add(255);
+    /// Index of the highest bit set, from `0` for ids that only differ in the
+    /// last bit up to `bits - 1` for ids that differ in the first bit. This is
+    /// the bucket of a flat Kademlia routing table the other id falls into.
+    ///
+    /// Returns `None` for equal ids.
+    pub fn bucket_index(&self) -> Option<usize> {
+        let bits = self.as_bytes().len() * 8;
+        let zeros = self.leading_zeros() as usize;
+        (zeros < bits).then(|| bits - zeros - 1)
     }
+}
 
-    distance
+impl<I: AsRef<[u8]>> Ord for Distance<I> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl<I: AsRef<[u8]>> PartialOrd for Distance<I> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<I: AsRef<[u8]>> PartialEq for Distance<I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<I: AsRef<[u8]>> Eq for Distance<I> {}
+
+impl<I: AsRef<[u8]>> std::fmt::Display for Distance<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<I: AsRef<[u8]>> std::fmt::Debug for Distance<I> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Distance({self})")
+    }
 }
 
 #[derive(Debug)]
@@ -51,20 +203,102 @@ enum Node<V: Contact> {
     Leaf {
         contacts: Vec<V>,
         dont_split: bool,
+        /// Recently seen contacts that did not fit, oldest first. Only full
+        /// buckets that may not be split have any.
+        replacements: Vec<V>,
+        /// When a contact was last added, updated or removed.
+        last_changed: Instant,
     },
 }
 
+/// The bits all ids in a bucket start with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Prefix {
+    /// Most significant bit first, padded with zeros to whole bytes.
+    bits: Vec<u8>,
+    len: u32,
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for bit_index in 0..self.len {
+            let byte = self.bits[(bit_index / 8) as usize];
+            let bit = byte >> (7 - bit_index % 8) & 1;
+            write!(f, "{bit}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Prefix {
+    /// Number of bits, the depth of the bucket in the tree.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Fills `id` with random bits, starting with the prefix.
+    pub fn random_id<R: RngCore + ?Sized>(&self, id: &mut [u8], rng: &mut R) {
+        rng.fill_bytes(id);
+        self.copy_to(id);
+    }
+
+    /// Fills `id` with the lowest id of the bucket: the prefix followed by
+    /// zeros.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn lowest_id(&self, id: &mut [u8]) {
+        id.fill(0);
+        self.copy_to(id);
+    }
+
+    /// Overwrites the first bits of `id` with the prefix.
+    fn copy_to(&self, id: &mut [u8]) {
+        for bit_index in 0..self.len.min(id.len() as u32 * 8) {
+            let byte = (bit_index / 8) as usize;
+            let mask = 1 << (7 - bit_index % 8);
+            id[byte] = (id[byte] & !mask) | (self.bits[byte] & mask);
+        }
+    }
+
+    fn child(&self, direction: Direction) -> Prefix {
+        let mut bits = self.bits.clone();
+        if self.len.is_multiple_of(8) {
+            bits.push(0);
+        }
+        if direction == Direction::Right {
+            bits[(self.len / 8) as usize] |= 1 << (7 - self.len % 8);
+        }
+        Prefix {
+            bits,
+            len: self.len + 1,
+        }
+    }
+}
+
 impl<V: Contact> Node<V> {
     fn contacts(&self) -> &[V] {
         match self {
             Node::Inner { .. } => &[][..],
-            Node::Leaf { contacts, .. } => &contacts,
+            Node::Leaf { contacts, .. } => contacts,
         }
     }
 }
 
 impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
     pub fn new(node_id: I, nodes_per_kbucket: Option<usize>, nodes_to_ping: Option<usize>) -> Self {
+        Self::with_arbiter(node_id, nodes_per_kbucket, nodes_to_ping, Latest)
+    }
+}
+
+impl<I: AsRef<[u8]>, V: Contact<Id = I>, A: Arbiter<V>> Kbucket<I, V, A> {
+    /// Like [`Kbucket::new`], with `arbiter` instead of [`Latest`] deciding
+    /// whether a known contact is replaced by the version added again.
+    pub fn with_arbiter(
+        node_id: I,
+        nodes_per_kbucket: Option<usize>,
+        nodes_to_ping: Option<usize>,
+        arbiter: A,
+    ) -> Self {
         let nodes_per_kbucket = nodes_per_kbucket.unwrap_or(20);
         let nodes_to_ping = nodes_to_ping.unwrap_or(3);
 
@@ -75,10 +309,24 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
             root: Node::Leaf {
                 contacts: Vec::new(),
                 dont_split: false,
+                replacements: Vec::new(),
+                last_changed: Instant::now(),
             },
+            events: None,
+            arbiter,
         }
     }
 
+    /// Returns a receiver for all changes from now on. A receiver that falls
+    /// more than [`EVENT_QUEUE_LEN`] events behind gets
+    /// [`RecvError::Lagged`](broadcast::error::RecvError::Lagged) and misses
+    /// the oldest events.
+    pub fn subscribe(&mut self) -> broadcast::Receiver<Event<V>> {
+        self.events
+            .get_or_insert_with(|| broadcast::channel(EVENT_QUEUE_LEN).0)
+            .subscribe()
+    }
+
     pub fn add(&mut self, contact: V) {
         let mut bit_index = 0u32;
         let mut node = &mut self.root;
@@ -97,7 +345,8 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
         let Node::Leaf {
             contacts,
             dont_split,
-            ..
+            replacements,
+            last_changed,
         } = node
         else {
             panic!("should not happen");
@@ -107,24 +356,61 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
         let index = index_of(contacts, contact.id());
 
         if let Some(index) = index {
-            update(contacts, index, contact);
+            if let Some((old, new)) = update(contacts, index, contact, &self.arbiter) {
+                *last_changed = Instant::now();
+                emit(&self.events, Event::Updated { old, new });
+            }
             return;
         }
 
         if contacts.len() < self.nodes_per_kbucket {
-            contacts.push(contact);
-            // this.emit('added', contact)
+            contacts.push(contact.clone());
+            *last_changed = Instant::now();
+            emit(&self.events, Event::Added(contact));
             return;
         }
 
         // the bucket is full
+        if let Some(index) = contacts.iter().position(Contact::is_bad) {
+            let bad = contacts.remove(index);
+            *last_changed = Instant::now();
+            emit(&self.events, Event::Removed(bad));
+            // it may wait in the replacement cache from an earlier attempt,
+            // where it would be promoted a second time
+            if let Some(index) = index_of(replacements, contact.id()) {
+                replacements.remove(index);
+            }
+            contacts.push(contact.clone());
+            emit(&self.events, Event::Added(contact));
+            return;
+        }
+
         if *dont_split {
             // we are not allowed to split the bucket
             // we need to ping the first this.numberOfNodesToPing
             // in order to determine if they are alive
             // only if one of the pinged nodes does not respond, can the new contact
             // be added (this prevents DoS flodding with new invalid contacts)
-            // this.emit('ping', node.contacts.slice(0, this.numberOfNodesToPing), contact)
+            // until then, it waits in the replacement cache
+            if let Some(index) = index_of(replacements, contact.id()) {
+                replacements.remove(index);
+            }
+            replacements.push(contact.clone());
+            if replacements.len() > self.nodes_per_kbucket {
+                replacements.remove(0);
+            }
+
+            // good contacts stay, if all of them are the new contact only
+            // waits for one to fail
+            let old: Vec<_> = contacts
+                .iter()
+                .filter(|contact| contact.status() == Status::Questionable)
+                .take(self.nodes_to_ping)
+                .cloned()
+                .collect();
+            if !old.is_empty() {
+                emit(&self.events, Event::Ping { old, new: contact });
+            }
             return;
         }
 
@@ -132,7 +418,8 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
         self.add(contact);
     }
 
-    /// Removes contact with the provided id.
+    /// Removes contact with the provided id, and promotes the most recently
+    /// seen replacement of its bucket in its place.
     pub fn remove(&mut self, id: I) {
         let mut bit_index = 0u32;
         let mut node = &mut self.root;
@@ -145,14 +432,25 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
             bit_index += 1;
         }
 
-        let Node::Leaf { contacts, .. } = node else {
+        let Node::Leaf {
+            contacts,
+            replacements,
+            last_changed,
+            ..
+        } = node
+        else {
             panic!("should not happen");
         };
 
         let index = index_of(contacts, &id);
         if let Some(index) = index {
             let contact = contacts.remove(index);
-            // this.emit('removed', contact)
+            *last_changed = Instant::now();
+            emit(&self.events, Event::Removed(contact));
+            if let Some(replacement) = replacements.pop() {
+                contacts.push(replacement.clone());
+                emit(&self.events, Event::Added(replacement));
+            }
         }
     }
 
@@ -180,6 +478,51 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
         index_of(contacts, &id).and_then(|i| contacts.get(i))
     }
 
+    /// Like [`Kbucket::get`], to update a contact in place. The contact keeps
+    /// its position in the bucket, and its id must not change.
+    pub fn get_mut(&mut self, id: I) -> Option<&mut V> {
+        let mut bit_index = 0u32;
+        let mut node = &mut self.root;
+
+        while let Node::Inner { left, right, .. } = node {
+            node = match determine_node(&id, bit_index) {
+                Direction::Left => left.as_mut(),
+                Direction::Right => right.as_mut(),
+            };
+            bit_index += 1;
+        }
+
+        let Node::Leaf { contacts, .. } = node else {
+            panic!("should not happen");
+        };
+
+        index_of(contacts, &id).and_then(|i| contacts.get_mut(i))
+    }
+
+    /// Returns the prefixes of the buckets that did not change within `span`,
+    /// and restarts their timers, so that they are returned again only after
+    /// another quiet `span`.
+    pub fn outdated(&mut self, span: Duration) -> Vec<Prefix> {
+        let mut outdated = Vec::new();
+        let mut nodes = vec![(&mut self.root, Prefix::default())];
+
+        while let Some((node, prefix)) = nodes.pop() {
+            match node {
+                Node::Inner { left, right } => {
+                    nodes.push((left.as_mut(), prefix.child(Direction::Left)));
+                    nodes.push((right.as_mut(), prefix.child(Direction::Right)));
+                }
+                Node::Leaf { last_changed, .. } => {
+                    if last_changed.elapsed() > span {
+                        *last_changed = Instant::now();
+                        outdated.push(prefix);
+                    }
+                }
+            }
+        }
+        outdated
+    }
+
     /// Counts the total number of contacts in the tree.
     pub fn len(&self) -> usize {
         let mut count = 0;
@@ -200,119 +543,269 @@ impl<I: AsRef<[u8]>, V: Contact<Id = I>> Kbucket<I, V> {
         count
     }
 
+    /// Whether the tree has no contacts.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
     /// Get the n closest contacts to the provided node id. "Closest" here means:
     /// closest according to the XOR metric of the contact node id.
+    ///
+    /// Only visits the buckets nearest to `id`, until `n` contacts are found,
+    /// and never holds more than `n` of them.
     pub fn closest(&self, id: I, n: Option<usize>) -> Vec<&V> {
-        let cap = n.unwrap_or(1);
-        let mut contacts = Vec::with_capacity(cap);
-        let mut nodes = vec![&self.root];
-        let mut bit_index = 0u32;
+        self.closest_filtered(id, n, |_| true)
+    }
 
-        while let Some(node) = nodes.pop() {
-            if let Some(n) = n {
-                if contacts.len() >= n {
-                    break;
-                }
+    /// Like [`Kbucket::closest`], but only contacts for which `filter`
+    /// returns `true` are included. Returns `n` contacts if there are enough
+    /// that pass.
+    pub fn closest_filtered<F>(&self, id: I, n: Option<usize>, mut filter: F) -> Vec<&V>
+    where
+        F: FnMut(&V) -> bool,
+    {
+        let n = n.unwrap_or(usize::MAX);
+        // every distance is computed once, and kept next to its contact
+        let mut contacts: Vec<(Distance<I>, &V)> =
+            Vec::with_capacity(n.min(self.nodes_per_kbucket));
+        // visit the buckets closest first: at every level, the branch sharing
+        // the bit of `id` is closer than the other one, so every bucket is
+        // further away than all buckets before it
+        let mut nodes = vec![(&self.root, 0u32)];
+
+        while let Some((node, bit_index)) = nodes.pop() {
+            let remaining = n - contacts.len();
+            if remaining == 0 {
+                break;
             }
             match node {
                 Node::Inner { left, right } => {
-                    match determine_node(&id, bit_index) {
-                        Direction::Left => {
-                            nodes.push(right);
-                            nodes.push(left);
+                    let (near, far) = match determine_node(&id, bit_index) {
+                        Direction::Left => (left, right),
+                        Direction::Right => (right, left),
+                    };
+                    nodes.push((far, bit_index + 1));
+                    nodes.push((near, bit_index + 1));
+                }
+                Node::Leaf {
+                    contacts: bucket, ..
+                } => {
+                    // only the contacts within the bucket need sorting
+                    let start = contacts.len();
+                    let mut candidates = bucket
+                        .iter()
+                        .filter(|contact| filter(contact))
+                        .map(|contact| (contact.distance(&id), contact));
+                    contacts.extend(candidates.by_ref().take(remaining));
+                    contacts[start..].sort_unstable_by(|a, b| a.0.cmp(&b.0));
+                    // keep the closest `remaining` of a bucket that does not fit
+                    for candidate in candidates {
+                        let last = contacts.len() - 1;
+                        if candidate.0 >= contacts[last].0 {
+                            continue;
                         }
-                        Direction::Right => {
-                            nodes.push(left);
-                            nodes.push(right);
+                        contacts[last] = candidate;
+                        let mut i = last;
+                        while i > start && contacts[i].0 < contacts[i - 1].0 {
+                            contacts.swap(i, i - 1);
+                            i -= 1;
                         }
                     }
-                    bit_index += 1;
-                }
-                Node::Leaf { contacts: c, .. } => {
-                    contacts.extend(c);
                 }
             }
         }
 
-        contacts.sort_by(|a, b| a.distance(&id).cmp(&b.distance(&id)));
+        contacts.into_iter().map(|(_, contact)| contact).collect()
+    }
 
-        if let Some(n) = n {
-            contacts.truncate(n);
+    /// Iterates over all contacts by increasing distance to `id`, for as long
+    /// as the caller needs more. A bucket is only sorted once the iteration
+    /// reaches it.
+    pub fn closest_iter(&self, id: I) -> ClosestIter<'_, I, V> {
+        ClosestIter {
+            id,
+            nodes: vec![(&self.root, 0)],
+            bucket: Vec::new(),
         }
-        contacts
     }
 
-    fn iter(&self) -> Iter<'_, V> {
+    /// Iterates over all contacts, bucket by bucket from near to far, see
+    /// [`Kbucket::buckets`]. Within a bucket, the least recently seen comes
+    /// first.
+    pub fn iter(&self) -> Iter<'_, I, V> {
         Iter {
-            nodes: vec![&self.root],
-            contacts: None,
-            i: 0,
+            buckets: self.buckets(),
+            contacts: [].iter(),
+        }
+    }
+
+    /// Iterates over the buckets from near to far: the bucket our own id
+    /// falls into comes first, then the buckets that share a shorter prefix
+    /// with it, down to the bucket of the ids that differ in the first bit.
+    pub fn buckets(&self) -> Buckets<'_, I, V> {
+        Buckets {
+            node_id: &self.node_id,
+            nodes: vec![(&self.root, Prefix::default())],
         }
     }
 }
 
+/// A bucket of a [`Kbucket`], see [`Kbucket::buckets`].
 #[derive(Debug)]
-pub struct Iter<'a, V: Contact> {
-    nodes: Vec<&'a Node<V>>,
-    contacts: Option<&'a [V]>,
-    i: usize,
+pub struct Bucket<'a, V> {
+    /// The bits all ids in the bucket start with.
+    pub prefix: Prefix,
+    /// Whether the bucket is far away from our own id, and not split when
+    /// full.
+    pub dont_split: bool,
+    /// Least recently seen first.
+    pub contacts: &'a [V],
 }
 
-impl<'a, V: Contact> Iterator for Iter<'a, V> {
-    type Item = &'a V;
+impl<V> Bucket<'_, V> {
+    /// Depth of the bucket in the tree, the length of its prefix.
+    pub fn depth(&self) -> u32 {
+        self.prefix.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct Buckets<'a, I, V: Contact> {
+    node_id: &'a I,
+    nodes: Vec<(&'a Node<V>, Prefix)>,
+}
+
+impl<'a, I: AsRef<[u8]>, V: Contact> Iterator for Buckets<'a, I, V> {
+    type Item = Bucket<'a, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(contacts) = self.contacts {
-                match contacts.get(self.i) {
-                    Some(contact) => {
-                        self.i += 1;
-                        return Some(contact);
-                    }
-                    None => {
-                        self.contacts = None;
-                        self.i = 0;
-                    }
+            let (node, prefix) = self.nodes.pop()?;
+            match node {
+                Node::Inner { left, right } => {
+                    // the branch our own id is in is the nearer one
+                    let (near, far) = match determine_node(self.node_id, prefix.len()) {
+                        Direction::Left => ((left, Direction::Left), (right, Direction::Right)),
+                        Direction::Right => ((right, Direction::Right), (left, Direction::Left)),
+                    };
+                    self.nodes.push((far.0, prefix.child(far.1)));
+                    self.nodes.push((near.0, prefix.child(near.1)));
+                }
+                Node::Leaf { dont_split, .. } => {
+                    return Some(Bucket {
+                        prefix,
+                        dont_split: *dont_split,
+                        contacts: node.contacts(),
+                    });
                 }
             }
+        }
+    }
+}
 
-            let node = self.nodes.pop()?;
-            match node {
-                Node::Inner { left, right } => {
-                    self.nodes.push(left.as_ref());
-                    self.nodes.push(right.as_ref());
+/// See [`Kbucket::iter`].
+#[derive(Debug)]
+pub struct Iter<'a, I, V: Contact> {
+    buckets: Buckets<'a, I, V>,
+    contacts: std::slice::Iter<'a, V>,
+}
+
+impl<'a, I: AsRef<[u8]>, V: Contact> Iterator for Iter<'a, I, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(contact) = self.contacts.next() {
+                return Some(contact);
+            }
+            self.contacts = self.buckets.next()?.contacts.iter();
+        }
+    }
+}
+
+/// See [`Kbucket::closest_iter`].
+#[derive(Debug)]
+pub struct ClosestIter<'a, I: AsRef<[u8]>, V: Contact> {
+    id: I,
+    /// Subtrees still to visit, the closest on top.
+    nodes: Vec<(&'a Node<V>, u32)>,
+    /// The rest of the current bucket with their distances, the closest last.
+    bucket: Vec<(Distance<I>, &'a V)>,
+}
+
+impl<'a, I: AsRef<[u8]>, V: Contact<Id = I>> Iterator for ClosestIter<'a, I, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((_, contact)) = self.bucket.pop() {
+                return Some(contact);
+            }
+            match self.nodes.pop()? {
+                (Node::Inner { left, right }, bit_index) => {
+                    let (near, far) = match determine_node(&self.id, bit_index) {
+                        Direction::Left => (left, right),
+                        Direction::Right => (right, left),
+                    };
+                    self.nodes.push((far, bit_index + 1));
+                    self.nodes.push((near, bit_index + 1));
                 }
-                Node::Leaf { contacts, .. } => {
-                    self.contacts = Some(&contacts);
-                    self.i = 0;
+                (Node::Leaf { contacts, .. }, _) => {
+                    let id = &self.id;
+                    self.bucket.extend(
+                        contacts
+                            .iter()
+                            .map(|contact| (contact.distance(id), contact)),
+                    );
+                    self.bucket.sort_unstable_by(|a, b| b.0.cmp(&a.0));
                 }
             }
         }
     }
 }
 
-fn update<V: Contact>(contacts: &mut Vec<V>, index: usize, contact: V) {
+/// Sends `event` to all subscribers, if there are any.
+fn emit<V>(events: &Option<broadcast::Sender<Event<V>>>, event: Event<V>) {
+    if let Some(events) = events {
+        events.send(event).ok();
+    }
+}
+
+/// Returns the old and the kept contact, if the contact was updated.
+fn update<V: Contact>(
+    contacts: &mut Vec<V>,
+    index: usize,
+    contact: V,
+    arbiter: &impl Arbiter<V>,
+) -> Option<(V, V)> {
     let incumbent = &contacts[index];
-    let should_replace = incumbent.should_replace(&contact);
+    let should_replace = arbiter.should_replace(incumbent, &contact);
 
     // if the selection is our old contact and the candidate is some new
     // contact, then there is nothing to do
     if !should_replace && incumbent != &contact {
-        return;
+        return None;
     }
 
     // remove old contact
     let old_contact = contacts.remove(index);
 
     // add more recent contact version
-    let to_insert = if should_replace { contact } else { old_contact };
-    contacts.push(to_insert);
-    // this.emit('updated', incumbent, selection)
+    let to_insert = if should_replace {
+        contact
+    } else {
+        old_contact.clone()
+    };
+    contacts.push(to_insert.clone());
+    Some((old_contact, to_insert))
 }
 
 /// Splits the node, redistributes contacts to the new nodes, and marks the
 /// node that was split as an inner node of the binary tree of nodes by
 /// setting this.root.contacts = null
+///
+/// Nodes that may be split have no replacements, there is nothing to
+/// redistribute.
 fn split<I: AsRef<[u8]>, V: Contact>(
     node_id: &I,
     contacts: &mut Vec<V>,
@@ -338,10 +831,14 @@ fn split<I: AsRef<[u8]>, V: Contact>(
         left: Box::new(Node::Leaf {
             contacts: left_contacts,
             dont_split: self_direction == Direction::Right,
+            replacements: Vec::new(),
+            last_changed: Instant::now(),
         }),
         right: Box::new(Node::Leaf {
             contacts: right_contacts,
             dont_split: self_direction == Direction::Left,
+            replacements: Vec::new(),
+            last_changed: Instant::now(),
         }),
     }
 }
@@ -393,6 +890,9 @@ enum Direction {
 
 #[cfg(test)]
 mod tests {
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
     use super::*;
 
     impl Contact for [u8; 1] {
@@ -416,6 +916,13 @@ mod tests {
         }
     }
 
+    impl Contact for [u8; 20] {
+        type Id = [u8; 20];
+        fn id(&self) -> &[u8; 20] {
+            self
+        }
+    }
+
     fn arr(a: u8) -> [u8; 1] {
         [a]
     }
@@ -502,6 +1009,7 @@ mod tests {
             Node::Leaf {
                 contacts,
                 dont_split,
+                ..
             } => {
                 assert!(!dont_split);
                 assert_eq!(contacts, vec![contact]);
@@ -513,13 +1021,16 @@ mod tests {
     #[test]
     fn test_adding_existing_contact_no_change_to_length() {
         let mut k_bucket = Kbucket::new([b'z'], None, None);
+        assert!(k_bucket.is_empty());
         let contact = [b'a'];
         k_bucket.add(contact);
         k_bucket.add([b'a']);
+        assert!(!k_bucket.is_empty());
         match k_bucket.root {
             Node::Leaf {
                 contacts,
                 dont_split,
+                ..
             } => {
                 assert!(!dont_split);
                 assert_eq!(contacts, vec![contact]);
@@ -538,6 +1049,7 @@ mod tests {
             Node::Leaf {
                 contacts,
                 dont_split,
+                ..
             } => {
                 assert_eq!(contacts.len(), 20);
                 assert!(!dont_split);
@@ -588,24 +1100,446 @@ mod tests {
         traverse(&k_bucket.root, false);
     }
 
-    // TODO: figure out ordering
-    // #[test]
-    // fn test_iter_all_contacts_sorted_low_high_buckets() {
-    //     let mut k_bucket = Kbucket::new([0x00u8, 0x01u8, 0u8], None, None);
-    //     let mut expected_ids = Vec::new();
-    //     for i in 0..k_bucket.nodes_per_kbucket {
-    //         k_bucket.add([0x80, i as u8, 0]); // make sure all go into "far away" bucket
-    //         expected_ids.push([0x80, i as u8, 0]);
-    //     }
-
-    //     // cause a split to happen
-    //     k_bucket.add([0, 0x80, 19]);
-
-    //     let contacts: Vec<_> = k_bucket.iter().collect();
-    //     assert_eq!(contacts.len(), k_bucket.nodes_per_kbucket + 1);
-    //     assert_eq!(contacts[0].id(), &[0, 0x80, 19]);
-    //     for (i, id) in contacts[1..].iter().enumerate() {
-    //         assert_eq!(**id, expected_ids[i])
-    //     }
-    // }
+    #[test]
+    fn test_events() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), Some(1));
+        let mut events = k_bucket.subscribe();
+
+        k_bucket.add([0x80]);
+        k_bucket.add([0x81]);
+        k_bucket.add([0x80]);
+        // the far bucket is full and may not be split
+        k_bucket.add([0x82]);
+        k_bucket.remove([0x81]);
+
+        assert_eq!(events.try_recv().unwrap(), Event::Added([0x80]));
+        assert_eq!(events.try_recv().unwrap(), Event::Added([0x81]));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Updated {
+                old: [0x80],
+                new: [0x80]
+            }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Ping {
+                old: vec![[0x81]],
+                new: [0x82]
+            }
+        );
+        assert_eq!(events.try_recv().unwrap(), Event::Removed([0x81]));
+        // promoted from the replacement cache
+        assert_eq!(events.try_recv().unwrap(), Event::Added([0x82]));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_events_without_subscribers() {
+        let mut k_bucket = Kbucket::new([0x00u8], None, None);
+        k_bucket.add([0x80]);
+        assert!(k_bucket.events.is_none());
+
+        // only changes from now on
+        let mut events = k_bucket.subscribe();
+        k_bucket.remove([0x80]);
+        assert_eq!(events.try_recv().unwrap(), Event::Removed([0x80]));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_slow_subscriber_lags() {
+        let mut k_bucket = Kbucket::new([0x00u8], None, None);
+        let mut events = k_bucket.subscribe();
+        for _ in 0..EVENT_QUEUE_LEN {
+            k_bucket.add([0x80]);
+            k_bucket.remove([0x80]);
+        }
+
+        // only the newest events are kept
+        assert_eq!(
+            events.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(
+                EVENT_QUEUE_LEN as u64
+            ))
+        );
+        for _ in 0..EVENT_QUEUE_LEN / 2 {
+            assert_eq!(events.try_recv().unwrap(), Event::Added([0x80]));
+            assert_eq!(events.try_recv().unwrap(), Event::Removed([0x80]));
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_replacements_are_promoted() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), Some(1));
+        for i in 0..6 {
+            k_bucket.add([0x80 + i]);
+        }
+        // seen again, now the most recent candidate
+        k_bucket.add([0x83]);
+        let mut events = k_bucket.subscribe();
+
+        match &k_bucket.root {
+            Node::Inner { right, .. } => match right.as_ref() {
+                Node::Leaf { replacements, .. } => {
+                    // bounded, the oldest candidates are gone
+                    assert_eq!(replacements, &vec![[0x85], [0x83]]);
+                }
+                Node::Inner { .. } => panic!("invalid split"),
+            },
+            Node::Leaf { .. } => panic!("invalid split"),
+        }
+
+        k_bucket.remove([0x80]);
+        k_bucket.remove([0x81]);
+        assert_eq!(events.try_recv().unwrap(), Event::Removed([0x80]));
+        assert_eq!(events.try_recv().unwrap(), Event::Added([0x83]));
+        assert_eq!(events.try_recv().unwrap(), Event::Removed([0x81]));
+        assert_eq!(events.try_recv().unwrap(), Event::Added([0x85]));
+        assert_eq!(k_bucket.len(), 2);
+        assert!(k_bucket.get([0x83]).is_some());
+        assert!(k_bucket.get([0x85]).is_some());
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct TestContact {
+        id: [u8; 1],
+        status: Status,
+    }
+
+    impl Contact for TestContact {
+        type Id = [u8; 1];
+        fn id(&self) -> &[u8; 1] {
+            &self.id
+        }
+        fn status(&self) -> Status {
+            self.status
+        }
+    }
+
+    fn node(id: u8, status: Status) -> TestContact {
+        TestContact { id: [id], status }
+    }
+
+    #[test]
+    fn test_bad_contacts_are_replaced() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(3), None);
+        k_bucket.add(node(0x80, Status::Questionable));
+        k_bucket.add(node(0x81, Status::Bad));
+        k_bucket.add(node(0x82, Status::Questionable));
+        let mut events = k_bucket.subscribe();
+
+        // splits, then replaces the bad contact instead of asking for a ping
+        k_bucket.add(node(0x83, Status::Questionable));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Removed(node(0x81, Status::Bad))
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Added(node(0x83, Status::Questionable))
+        );
+        assert!(events.try_recv().is_err());
+        assert_eq!(k_bucket.len(), 3);
+        assert!(k_bucket.get([0x81]).is_none());
+    }
+
+    #[test]
+    fn test_bad_contact_replaced_by_waiting_replacement() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), Some(1));
+        k_bucket.add(node(0x80, Status::Questionable));
+        k_bucket.add(node(0x81, Status::Questionable));
+        // waits in the replacement cache
+        k_bucket.add(node(0x82, Status::Questionable));
+
+        k_bucket.get_mut([0x80]).unwrap().status = Status::Bad;
+        k_bucket.add(node(0x82, Status::Questionable));
+        k_bucket.remove([0x81]);
+        let contacts: Vec<_> = k_bucket.iter().collect();
+        assert_eq!(contacts, vec![&node(0x82, Status::Questionable)]);
+    }
+
+    #[test]
+    fn test_good_contacts_are_not_pinged() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), Some(2));
+        k_bucket.add(node(0x80, Status::Good));
+        k_bucket.add(node(0x81, Status::Good));
+        let mut events = k_bucket.subscribe();
+
+        // splits, the far bucket is full of good contacts
+        k_bucket.add(node(0x82, Status::Questionable));
+        assert!(events.try_recv().is_err());
+        assert!(k_bucket.get([0x82]).is_none());
+
+        // only the questionable one is pinged, even though it is not the
+        // least recently seen
+        k_bucket.get_mut([0x81]).unwrap().status = Status::Questionable;
+        k_bucket.add(node(0x83, Status::Questionable));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Ping {
+                old: vec![node(0x81, Status::Questionable)],
+                new: node(0x83, Status::Questionable)
+            }
+        );
+
+        // the candidates waited in the replacement cache
+        k_bucket.remove([0x81]);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Removed(node(0x81, Status::Questionable))
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Added(node(0x83, Status::Questionable))
+        );
+    }
+
+    #[test]
+    fn test_arbiter() {
+        // bad versions never replace good ones
+        let arbiter = |_: &TestContact, candidate: &TestContact| candidate.status != Status::Bad;
+        let mut k_bucket = Kbucket::with_arbiter([0x00u8], None, None, arbiter);
+        k_bucket.add(node(0x80, Status::Questionable));
+        k_bucket.add(node(0x81, Status::Questionable));
+        let mut events = k_bucket.subscribe();
+
+        k_bucket.add(node(0x80, Status::Bad));
+        assert!(events.try_recv().is_err());
+        assert_eq!(
+            k_bucket.get([0x80]),
+            Some(&node(0x80, Status::Questionable))
+        );
+        // not moved to the end as the most recently seen
+        assert_eq!(
+            k_bucket.iter().next(),
+            Some(&node(0x80, Status::Questionable))
+        );
+
+        // the same version is still moved to the end
+        k_bucket.add(node(0x80, Status::Questionable));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Updated {
+                old: node(0x80, Status::Questionable),
+                new: node(0x80, Status::Questionable)
+            }
+        );
+        assert_eq!(
+            k_bucket.iter().last(),
+            Some(&node(0x80, Status::Questionable))
+        );
+
+        k_bucket.add(node(0x81, Status::Bad));
+        k_bucket.add(node(0x81, Status::Questionable));
+        assert_eq!(
+            events.try_recv().unwrap(),
+            Event::Updated {
+                old: node(0x81, Status::Questionable),
+                new: node(0x81, Status::Questionable)
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_outdated_buckets() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), None);
+        for i in [0x00, 0x01, 0x80] {
+            k_bucket.add([i]);
+        }
+        std::thread::sleep(Duration::from_millis(30));
+        k_bucket.add([0x81]);
+
+        // only the bucket with 0x00 and 0x01 was quiet
+        let outdated = k_bucket.outdated(Duration::from_millis(20));
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].len(), 1);
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let mut id = [0u8; 2];
+            outdated[0].random_id(&mut id, &mut rng);
+            assert_eq!(id[0] & 0x80, 0);
+        }
+
+        // not again until another quiet span
+        assert!(k_bucket.outdated(Duration::from_millis(20)).is_empty());
+    }
+
+    #[test]
+    fn test_prefix_random_id() {
+        let prefix = Prefix::default()
+            .child(Direction::Right)
+            .child(Direction::Left)
+            .child(Direction::Right);
+        let mut rng = rand::thread_rng();
+        for _ in 0..10 {
+            let mut id = [0u8; 20];
+            prefix.random_id(&mut id, &mut rng);
+            assert_eq!(id[0] >> 5, 0b101);
+        }
+
+        let long = (0..12).fold(Prefix::default(), |prefix, _| {
+            prefix.child(Direction::Right)
+        });
+        let mut id = [0u8; 2];
+        long.random_id(&mut id, &mut rng);
+        assert_eq!(id[0], 0xff);
+        assert_eq!(id[1] >> 4, 0xf);
+    }
+
+    #[test]
+    fn test_prefix_lowest_id() {
+        let prefix = Prefix::default()
+            .child(Direction::Right)
+            .child(Direction::Left)
+            .child(Direction::Right);
+        let mut id = [0xffu8; 2];
+        prefix.lowest_id(&mut id);
+        assert_eq!(id, [0b1010_0000, 0]);
+
+        Prefix::default().lowest_id(&mut id);
+        assert_eq!(id, [0, 0]);
+    }
+
+    /// Naive reference: the distance as a big integer.
+    fn reference(a: &[u8], b: &[u8]) -> BigUint {
+        BigUint::from_bytes_be(a) ^ BigUint::from_bytes_be(b)
+    }
+
+    #[test]
+    fn test_distance() {
+        let d = Distance::between(&[0x00, 0x0f], &[0x00, 0x1f]);
+        assert_eq!(d.as_bytes(), &[0x00, 0x10]);
+        assert_eq!(d.leading_zeros(), 11);
+        assert_eq!(d.bucket_index(), Some(4));
+        assert_eq!(d.to_string(), "0010");
+
+        let zero = Distance::between(&[1, 2], &[1, 2]);
+        assert_eq!(zero.leading_zeros(), 16);
+        assert_eq!(zero.bucket_index(), None);
+        assert!(zero < d);
+
+        // differs in the first byte only, which the old usize distance lost:
+        // its `wrapping_mul(256)` only kept the last 8 bytes
+        let a = Distance::between(&[1; 20], &[0; 20]);
+        let mut b = [1; 20];
+        b[0] = 2;
+        let b = Distance::between(&b, &[0; 20]);
+        assert!(a < b);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_distance_matches_reference(
+            a in any::<[u8; 20]>(),
+            b in any::<[u8; 20]>(),
+            target in any::<[u8; 20]>(),
+        ) {
+            let (da, db) = (a.distance(&target), b.distance(&target));
+            let (ra, rb) = (reference(&a, &target), reference(&b, &target));
+            prop_assert_eq!(da.cmp(&db), ra.cmp(&rb));
+            prop_assert_eq!(da == db, ra == rb);
+            prop_assert_eq!(da.leading_zeros() as u64, 160 - ra.bits());
+            prop_assert_eq!(
+                da.bucket_index().map(|i| i as u64),
+                ra.bits().checked_sub(1)
+            );
+            prop_assert_eq!(da.to_string(), format!("{ra:040x}"));
+        }
+
+        #[test]
+        fn prop_distance_ord_is_numeric(a in any::<[u8; 24]>(), b in any::<[u8; 24]>()) {
+            let (da, db) = (Distance(a), Distance(b));
+            let (ra, rb) = (BigUint::from_bytes_be(&a), BigUint::from_bytes_be(&b));
+            prop_assert_eq!(da.cmp(&db), ra.cmp(&rb));
+        }
+
+        #[test]
+        fn prop_closest_matches_reference(
+            node_id in any::<[u8; 20]>(),
+            ids in prop::collection::vec(any::<[u8; 20]>(), 0..200),
+            target in any::<[u8; 20]>(),
+            n in 1..50usize,
+        ) {
+            let mut k_bucket = Kbucket::new(node_id, None, None);
+            for id in ids {
+                k_bucket.add(id);
+            }
+
+            let mut expected: Vec<_> = k_bucket.iter().copied().collect();
+            expected.sort_by_key(|id| reference(id, &target));
+            let all: Vec<_> = k_bucket.closest(target, None).into_iter().copied().collect();
+            prop_assert_eq!(&all, &expected);
+            let iter: Vec<_> = k_bucket.closest_iter(target).copied().collect();
+            prop_assert_eq!(&iter, &expected);
+            let odd = |id: &[u8; 20]| id[19] % 2 == 1;
+            let filtered: Vec<_> = k_bucket
+                .closest_filtered(target, Some(n), |id| odd(id))
+                .into_iter()
+                .copied()
+                .collect();
+            let mut expected_filtered: Vec<_> = expected.iter().copied().filter(odd).collect();
+            expected_filtered.truncate(n);
+            prop_assert_eq!(filtered, expected_filtered);
+            expected.truncate(n);
+            let closest: Vec<_> = k_bucket.closest(target, Some(n)).into_iter().copied().collect();
+            prop_assert_eq!(closest, expected);
+        }
+    }
+
+    #[test]
+    fn test_iter_all_contacts_sorted_low_high_buckets() {
+        let mut k_bucket = Kbucket::new([0x00u8, 0x01u8, 0u8], None, None);
+        let mut expected_ids = Vec::new();
+        for i in 0..k_bucket.nodes_per_kbucket {
+            k_bucket.add([0x80, i as u8, 0]); // make sure all go into "far away" bucket
+            expected_ids.push([0x80, i as u8, 0]);
+        }
+
+        // cause a split to happen
+        k_bucket.add([0, 0x80, 19]);
+
+        let contacts: Vec<_> = k_bucket.iter().collect();
+        assert_eq!(contacts.len(), k_bucket.nodes_per_kbucket + 1);
+        assert_eq!(contacts[0].id(), &[0, 0x80, 19]);
+        for (i, id) in contacts[1..].iter().enumerate() {
+            assert_eq!(**id, expected_ids[i])
+        }
+    }
+
+    #[test]
+    fn test_buckets_near_to_far() {
+        let mut k_bucket = Kbucket::new([0x00u8], Some(2), None);
+        for i in [0x80, 0x81, 0x40, 0x41, 0x01, 0x02] {
+            k_bucket.add([i]);
+        }
+
+        let buckets: Vec<_> = k_bucket
+            .buckets()
+            .map(|bucket| {
+                (
+                    bucket.prefix.to_string(),
+                    bucket.depth(),
+                    bucket.dont_split,
+                    bucket.contacts.to_vec(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            vec![
+                ("00".to_string(), 2, false, vec![[0x01], [0x02]]),
+                ("01".to_string(), 2, true, vec![[0x40], [0x41]]),
+                ("1".to_string(), 1, true, vec![[0x80], [0x81]]),
+            ]
+        );
+
+        let distances: Vec<_> = k_bucket
+            .iter()
+            .map(|contact| contact.distance(&[0x00]))
+            .collect();
+        assert!(distances.windows(2).all(|pair| pair[0] < pair[1]));
+    }
 }
@@ -1,42 +1,103 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures::Stream;
 use rand::RngCore;
-use tokio::sync::mpsc;
-use tokio::task::JoinHandle;
-use url::Url;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use url::{Host, Url};
 
+use self::kbucket::{Contact as _, Kbucket};
+use self::lookup::Lookup;
 use self::records::Records;
-use self::rpc::Rpc;
-use self::tables::Tables;
+use self::rpc::{ErrorMessage, Event, NodeInfo, Query, Response, Rpc};
+use self::tables::{Contact, StableAddr, Table, Tables};
 use self::values::Values;
 
+mod bencode;
+mod hash;
+/// The routing table, public for the benchmarks only.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod kbucket;
+#[cfg(not(feature = "bench"))]
 mod kbucket;
+mod lookup;
 mod records;
 mod rpc;
 mod tables;
 mod values;
 
+pub use self::hash::{Hash, Id, Sha1, Sha256};
+pub use self::kbucket::{Distance, Event as RoutingEvent};
+pub use self::rpc::RpcStats;
+
 /// Rotate secrets every 5 minutes
 const ROTATE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-// TODO: make Hash generic and configurable, for now just use sha1
-pub(crate) const HASH_LENGTH: usize = 20;
+/// Number of nodes per bucket, and of closest nodes a lookup converges on
+const K: usize = 20;
+
+/// Port used for bootstrap urls without an explicit port
+const DEFAULT_PORT: u16 = 6881;
 
-pub struct Dht {
-    actor_sender: mpsc::Sender<ActorMessage>,
+/// A DHT node, with ids and tokens of hash function `H`.
+///
+/// [`Sha1`] is the BitTorrent DHT. Nodes can only talk to nodes that use
+/// the same hash.
+pub struct Dht<H: Hash = Sha1> {
+    actor_sender: mpsc::Sender<ActorMessage<H::Id>>,
     actor_handle: JoinHandle<()>,
+    local_addr: SocketAddr,
+    bootstrapped: watch::Receiver<Option<usize>>,
+}
+
+/// Snapshot of a bucket of the routing table, see [`Dht::buckets`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketInfo<I = [u8; 20]> {
+    /// The bits all node ids in the bucket start with, e.g. `"0110"`.
+    pub prefix: String,
+    /// Depth of the bucket in the tree, the length of its prefix.
+    pub depth: u32,
+    /// Whether the bucket is far away from our own id, and not split when full.
+    pub dont_split: bool,
+    /// Ids and addresses of the nodes, least recently seen first.
+    pub nodes: Vec<(I, SocketAddr)>,
+}
+
+/// Number of routing events a [`Dht::routing_events`] subscriber missed,
+/// because it fell too far behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged(pub u64);
+
+impl std::fmt::Display for Lagged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missed {} routing events", self.0)
+    }
 }
 
-pub struct Opts {
-    /// 160-bit DHT node ID (default: randomly generated)
-    pub node_id: Option<[u8; 20]>,
-    /// Bootstrap servers (default: router.bittorrent.com:6881, router.utorrent.com:6881, dht.transmissionbt.com:6881)
+impl std::error::Error for Lagged {}
+
+pub struct Opts<H: Hash = Sha1> {
+    /// DHT node ID, 160-bit for SHA-1 (default: randomly generated)
+    pub node_id: Option<H::Id>,
+    /// Address to listen on, IPv4 or IPv6 (default: 0.0.0.0:0, any interface with a random port)
+    pub bind_addr: SocketAddr,
+    /// Already bound socket to use instead of binding to `bind_addr`
+    pub socket: Option<std::net::UdpSocket>,
+    /// Bootstrap servers (default: [`Hash::DEFAULT_BOOTSTRAP`], router.bittorrent.com:6881, router.utorrent.com:6881 and dht.transmissionbt.com:6881 for SHA-1, none otherwise)
     pub bootstrap: Vec<Url>,
     /// Host of local peer, if specified then announces get added to local table (disabled by default)
+    ///
+    /// Only the host part is used, e.g. `udp://203.0.113.7`. The port is the one passed to [`Dht::announce`].
     pub host: Option<Url>,
-    /// k-rpc option to specify maximum concurrent UDP requests allowed.
+    /// k-rpc option to specify maximum concurrent UDP requests allowed, further queries wait in line.
     pub concurrency: usize,
+    /// k-rpc option to specify how long to wait for a response to a query.
+    pub timeout: Duration,
     /// Check buckets
     pub time_bucket_outdated: Duration,
     pub max_tables: usize,
@@ -46,13 +107,19 @@ pub struct Opts {
     pub max_peers: usize,
 }
 
-impl Default for Opts {
+impl<H: Hash> Default for Opts<H> {
     fn default() -> Self {
         Opts {
             node_id: None,
-            bootstrap: vec![],
+            bind_addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            socket: None,
+            bootstrap: H::DEFAULT_BOOTSTRAP
+                .iter()
+                .map(|url| Url::parse(url).expect("valid url"))
+                .collect(),
             host: None,
             concurrency: 16,
+            timeout: Duration::from_secs(2),
             time_bucket_outdated: Duration::from_secs(15 * 16),
             max_tables: 1000,
             max_values: 1000,
@@ -63,10 +130,20 @@ impl Default for Opts {
 }
 
 impl Dht {
+    /// Creates the node and starts listening on [`Opts::bind_addr`] or [`Opts::socket`].
     pub async fn new<R: RngCore + Send + 'static>(opts: Opts, rng: R) -> Result<Self> {
+        Self::with_hash(opts, rng).await
+    }
+}
+
+impl<H: Hash> Dht<H> {
+    /// Like [`Dht::new`], for a DHT with hash `H`, e.g. `Dht::<Sha256>::with_hash`.
+    pub async fn with_hash<R: RngCore + Send + 'static>(opts: Opts<H>, rng: R) -> Result<Self> {
         let (actor_sender, actor_receiver) = mpsc::channel(64);
 
-        let actor = Actor::new(opts, rng)?;
+        let (bootstrapped_sender, bootstrapped) = watch::channel(None);
+        let actor = Actor::new(opts, rng, bootstrapped_sender).await?;
+        let local_addr = actor.rpc.local_addr()?;
 
         let actor_handle = tokio::task::spawn(async move {
             actor.run(actor_receiver).await;
@@ -75,9 +152,128 @@ impl Dht {
         Ok(Dht {
             actor_sender,
             actor_handle,
+            local_addr,
+            bootstrapped,
         })
     }
 
+    /// Waits until the bootstrap lookup for our own id has finished, and
+    /// returns the number of nodes that responded to it.
+    ///
+    /// The lookup runs in the background after [`Dht::new`], and finishes
+    /// even if none of the [`Opts::bootstrap`] nodes could be reached. While
+    /// the routing table stays empty, e.g. because we were offline, it is run
+    /// again whenever buckets are checked for a refresh. Calls after such a
+    /// retry return its count.
+    pub async fn bootstrapped(&self) -> Result<usize> {
+        let mut bootstrapped = self.bootstrapped.clone();
+        let found = bootstrapped.wait_for(Option::is_some).await?;
+        Ok(found.unwrap_or_default())
+    }
+
+    /// Looks up peers for `info_hash`.
+    ///
+    /// Peers are yielded as the lookup finds them, each one only once. The
+    /// stream ends when the lookup has converged on the closest nodes.
+    pub async fn get_peers(&self, info_hash: H::Id) -> Result<impl Stream<Item = SocketAddr>> {
+        let (s, mut r) = mpsc::unbounded_channel();
+        self.actor_sender
+            .send(ActorMessage::GetPeers {
+                info_hash,
+                peers: s,
+            })
+            .await?;
+        Ok(futures::stream::poll_fn(move |cx| r.poll_recv(cx)))
+    }
+
+    /// Announces that we are a peer for `info_hash`, listening on `port`.
+    ///
+    /// Sends announce_peer to the closest nodes that gave us a token, from
+    /// a recent [`Dht::get_peers`] or else from a new lookup. With
+    /// `implied_port`, nodes use the source port of our packets instead of
+    /// `port`. Returns the nodes that accepted the announce.
+    pub async fn announce(
+        &self,
+        info_hash: H::Id,
+        port: u16,
+        implied_port: bool,
+    ) -> Result<Vec<SocketAddr>> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender
+            .send(ActorMessage::Announce {
+                info_hash,
+                port,
+                implied_port,
+                s,
+            })
+            .await?;
+        Ok(r.await?)
+    }
+
+    /// Number of nodes in the routing table.
+    pub async fn routing_table_len(&self) -> Result<usize> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender
+            .send(ActorMessage::RoutingTableLen(s))
+            .await?;
+        Ok(r.await?)
+    }
+
+    /// Changes to the routing table from now on, with the id and address of
+    /// each node. The stream ends when the node shuts down.
+    ///
+    /// Only a limited number of events is buffered. A consumer that falls
+    /// behind gets [`Lagged`] with the number of events it missed, and
+    /// continues with the oldest event still buffered.
+    pub async fn routing_events(
+        &self,
+    ) -> Result<impl Stream<Item = Result<RoutingEvent<(H::Id, SocketAddr)>, Lagged>>> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender
+            .send(ActorMessage::RoutingEvents(s))
+            .await?;
+        let events = r.await?;
+        Ok(Box::pin(futures::stream::unfold(
+            events,
+            |mut events| async move {
+                let event = match events.recv().await {
+                    Ok(event) => Ok(event.map(|contact| (*contact.id(), contact.addr()))),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Err(Lagged(missed)),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                };
+                Some((event, events))
+            },
+        )))
+    }
+
+    /// Ids and addresses of all nodes in the routing table, bucket by bucket
+    /// in the order of [`Dht::buckets`].
+    pub async fn nodes(&self) -> Result<Vec<(H::Id, SocketAddr)>> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender.send(ActorMessage::Nodes(s)).await?;
+        Ok(r.await?)
+    }
+
+    /// The buckets of the routing table, from the one our own id falls into
+    /// to the one furthest away.
+    pub async fn buckets(&self) -> Result<Vec<BucketInfo<H::Id>>> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender.send(ActorMessage::Buckets(s)).await?;
+        Ok(r.await?)
+    }
+
+    /// The address the node is listening on, with the actual port if it was bound to port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Load of the outgoing query queue, limited by [`Opts::concurrency`].
+    pub async fn rpc_stats(&self) -> Result<RpcStats> {
+        let (s, r) = oneshot::channel();
+        self.actor_sender.send(ActorMessage::RpcStats(s)).await?;
+        Ok(r.await?)
+    }
+
     pub async fn shutdown(self) -> Result<()> {
         self.actor_sender.send(ActorMessage::Shutdown).await.ok();
         self.actor_handle.await?;
@@ -85,58 +281,151 @@ impl Dht {
     }
 }
 
-enum ActorMessage {
+enum ActorMessage<I> {
     Shutdown,
+    RpcStats(oneshot::Sender<RpcStats>),
+    RoutingTableLen(oneshot::Sender<usize>),
+    Nodes(oneshot::Sender<Vec<(I, SocketAddr)>>),
+    RoutingEvents(oneshot::Sender<broadcast::Receiver<kbucket::Event<Contact<I>>>>),
+    Buckets(oneshot::Sender<Vec<BucketInfo<I>>>),
+    GetPeers {
+        info_hash: I,
+        peers: mpsc::UnboundedSender<SocketAddr>,
+    },
+    Announce {
+        info_hash: I,
+        port: u16,
+        implied_port: bool,
+        s: oneshot::Sender<Vec<SocketAddr>>,
+    },
+}
+
+type Nodes<I> = Vec<NodeInfo<I>>;
+
+/// What a background task hands back to the actor.
+enum TaskOutput<I: Id> {
+    Done,
+    /// The table of a lookup for a target, to be kept in `tables`.
+    Table(I, Table<I>),
+    /// Contacts of a full bucket were pinged. The ones in `dead` did not
+    /// respond.
+    Pinged {
+        pinged: Vec<I>,
+        dead: Vec<I>,
+    },
+    /// A node that sent us a query was pinged, see `Actor::verify`.
+    Verified(SocketAddr),
+    /// The bootstrap lookup finished.
+    Bootstrapped,
 }
 
-struct Actor {
-    tables: Tables,
-    values: Values,
-    peers: Records,
-    rpc: Rpc,
-    secrets: Secrets,
-    host: Option<Url>,
-    listening: bool,
-    destroyed: bool,
-    node_id: [u8; 20],
+struct Actor<H: Hash> {
+    /// The routing table
+    nodes: Kbucket<H::Id, Contact<H::Id>, StableAddr>,
+    node_events: broadcast::Receiver<kbucket::Event<Contact<H::Id>>>,
+    /// Contacts being pinged to make room in a full bucket
+    pinging: HashSet<H::Id>,
+    /// Unknown nodes that sent us a query, pinged before they are added
+    verifying: HashSet<SocketAddr>,
+    /// Limit of `verifying`, half of the query slots, so that lookups are
+    /// not starved by queries from nodes that never answer
+    max_verifying: usize,
+    /// Ids of the routing table contacts by address, kept in sync through
+    /// `node_events`, to find the contact a timed out query was sent to
+    addrs: HashMap<SocketAddr, H::Id>,
+    tables: Tables<H::Id>,
+    /// Not read until get and put (BEP44) are handled, they are answered
+    /// with "method unknown" for now
+    #[allow(dead_code)]
+    values: Values<H::Id>,
+    peers: Records<H::Id>,
+    rpc: Rpc<H::Id>,
+    rpc_events: mpsc::Receiver<Event<H::Id>>,
+    secrets: Secrets<H>,
+    /// Our own address for local announces, from [`Opts::host`]
+    host: Option<IpAddr>,
+    node_id: H::Id,
     bucket_outdated_time_span: Duration,
     rng: Box<dyn RngCore + Send + 'static>,
+    bootstrap: Vec<Url>,
+    /// Whether a bootstrap lookup is running
+    bootstrapping: bool,
+    bootstrapped: watch::Sender<Option<usize>>,
+    /// Background lookups and pings, aborted when the actor stops
+    tasks: JoinSet<TaskOutput<H::Id>>,
 }
 
-impl Actor {
-    fn new<R: RngCore + Send + 'static>(opts: Opts, mut rng: R) -> Result<Self> {
-        let rpc = Rpc::new();
-        // TODO: register "callbacks" to rpc
+impl<H: Hash> Actor<H> {
+    async fn new<R: RngCore + Send + 'static>(
+        opts: Opts<H>,
+        mut rng: R,
+        bootstrapped: watch::Sender<Option<usize>>,
+    ) -> Result<Self> {
+        let socket = match opts.socket {
+            Some(socket) => {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)?
+            }
+            None => UdpSocket::bind(opts.bind_addr).await?,
+        };
+        let (rpc, rpc_events) = Rpc::new(socket, opts.timeout, opts.concurrency);
         // TODO: integrate verify "callback" (probably a trait)
 
+        let host = match &opts.host {
+            Some(url) => {
+                let addrs = resolve(url).await.context("invalid host")?;
+                Some(addrs.first().context("host did not resolve")?.ip())
+            }
+            None => None,
+        };
+
         let node_id = opts.node_id.unwrap_or_else(|| {
-            let mut bytes = [0u8; 20];
-            rng.fill_bytes(&mut bytes);
-            bytes
+            let mut id = H::Id::default();
+            rng.fill_bytes(id.as_mut());
+            id
         });
 
+        let mut nodes = Kbucket::with_arbiter(node_id, Some(K), None, StableAddr);
+        let node_events = nodes.subscribe();
+
         Ok(Actor {
+            nodes,
+            node_events,
+            pinging: HashSet::new(),
+            verifying: HashSet::new(),
+            max_verifying: (opts.concurrency / 2).max(1),
+            addrs: HashMap::new(),
             tables: Tables::new(ROTATE_INTERVAL, opts.max_tables)?,
             values: Values::new(opts.max_values)?,
             peers: Records::new(opts.max_age, opts.max_peers),
             secrets: Secrets::new(&mut rng),
             rpc,
-            host: opts.host,
-            listening: false,
-            destroyed: false,
+            rpc_events,
+            host,
             node_id,
             bucket_outdated_time_span: opts.time_bucket_outdated,
             rng: Box::new(rng),
+            bootstrap: opts.bootstrap,
+            bootstrapping: false,
+            bootstrapped,
+            tasks: JoinSet::new(),
         })
     }
 
-    async fn run(mut self, mut actor_receiver: mpsc::Receiver<ActorMessage>) {
+    async fn run(mut self, mut actor_receiver: mpsc::Receiver<ActorMessage<H::Id>>) {
+        self.bootstrap();
+
         // Setup interval to trigger secret rotation
         let mut interval = tokio::time::interval_at(
             tokio::time::Instant::now() + ROTATE_INTERVAL,
             ROTATE_INTERVAL,
         );
 
+        // Check for outdated buckets a few times per span
+        let check = (self.bucket_outdated_time_span / 4).max(Duration::from_millis(1));
+        let mut refresh_interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + check, check);
+
         loop {
             tokio::select! {
                 biased;
@@ -146,49 +435,1466 @@ impl Actor {
                         ActorMessage::Shutdown => {
                             break;
                         }
+                        ActorMessage::RpcStats(s) => {
+                            s.send(self.rpc.stats()).ok();
+                        }
+                        ActorMessage::RoutingTableLen(s) => {
+                            s.send(self.nodes.len()).ok();
+                        }
+                        ActorMessage::RoutingEvents(s) => {
+                            s.send(self.nodes.subscribe()).ok();
+                        }
+                        ActorMessage::Nodes(s) => {
+                            let nodes = self
+                                .nodes
+                                .iter()
+                                .map(|contact| (*contact.id(), contact.addr()))
+                                .collect();
+                            s.send(nodes).ok();
+                        }
+                        ActorMessage::Buckets(s) => {
+                            let buckets = self
+                                .nodes
+                                .buckets()
+                                .map(|bucket| BucketInfo {
+                                    prefix: bucket.prefix.to_string(),
+                                    depth: bucket.depth(),
+                                    dont_split: bucket.dont_split,
+                                    nodes: bucket
+                                        .contacts
+                                        .iter()
+                                        .map(|contact| (*contact.id(), contact.addr()))
+                                        .collect(),
+                                })
+                                .collect();
+                            s.send(buckets).ok();
+                        }
+                        ActorMessage::GetPeers { info_hash, peers } => {
+                            let seeds = self.closest_nodes(&info_hash);
+                            self.tasks.spawn(get_peers(
+                                self.rpc.clone(),
+                                self.node_id,
+                                info_hash,
+                                seeds,
+                                peers,
+                            ));
+                        }
+                        ActorMessage::Announce { info_hash, port, implied_port, s } => {
+                            if let Some(ip) = self.host {
+                                let port = if implied_port {
+                                    self.rpc.local_addr().map_or(port, |addr| addr.port())
+                                } else {
+                                    port
+                                };
+                                self.peers.add(info_hash, SocketAddr::new(ip, port));
+                            }
+                            // a lookup that found no one, e.g. before bootstrap,
+                            // leaves nothing to announce to
+                            let cached = self
+                                .tables
+                                .get(&info_hash)
+                                .map(|table| {
+                                    table
+                                        .closest_filtered(info_hash, Some(K), |contact| {
+                                            contact.token().is_some()
+                                        })
+                                        .into_iter()
+                                        .cloned()
+                                        .collect::<Vec<_>>()
+                                })
+                                .filter(|contacts| !contacts.is_empty());
+                            let seeds = self.closest_nodes(&info_hash);
+                            self.tasks.spawn(announce(
+                                self.rpc.clone(),
+                                self.node_id,
+                                Announce { info_hash, port, implied_port },
+                                seeds,
+                                cached,
+                                s,
+                            ));
+                        }
                     }
                 }
+                // before incoming packets, which add more events
+                event = self.node_events.recv() => {
+                    match event {
+                        Ok(event) => self.handle_node_event(event),
+                        Err(_) => self.reindex_addrs(),
+                    }
+                }
+                Some(event) = self.rpc_events.recv() => {
+                    self.handle_rpc_event(event).await;
+                }
                 _ = interval.tick() => {
                     self.secrets.rotate(&mut self.rng);
                 }
+                _ = refresh_interval.tick() => {
+                    self.refresh_buckets();
+                    // no bootstrap node answered, try again until one does
+                    if !self.bootstrapping
+                        && !self.bootstrap.is_empty()
+                        && self.nodes.is_empty()
+                    {
+                        self.bootstrap();
+                    }
+                }
+                Some(result) = self.tasks.join_next() => {
+                    match result {
+                        Ok(TaskOutput::Table(target, table)) => {
+                            self.tables.insert(target, table);
+                        }
+                        Ok(TaskOutput::Pinged { pinged, dead }) => {
+                            for id in &pinged {
+                                self.pinging.remove(id);
+                            }
+                            // the bucket's replacements take their place
+                            for id in dead {
+                                self.nodes.remove(id);
+                            }
+                        }
+                        Ok(TaskOutput::Verified(addr)) => {
+                            self.verifying.remove(&addr);
+                        }
+                        Ok(TaskOutput::Bootstrapped) => {
+                            self.bootstrapping = false;
+                        }
+                        Ok(TaskOutput::Done) | Err(_) => {}
+                    }
+                }
                 else => {
                     break;
                 }
             }
         }
     }
+
+    /// Starts the lookup for our own id through the [`Opts::bootstrap`]
+    /// nodes.
+    fn bootstrap(&mut self) {
+        self.bootstrapping = true;
+        let bootstrap = bootstrap(
+            self.rpc.clone(),
+            self.node_id,
+            self.closest_nodes(&self.node_id),
+            self.bootstrap.clone(),
+            self.bootstrapped.clone(),
+        );
+        self.tasks.spawn(async move {
+            bootstrap.await;
+            TaskOutput::Bootstrapped
+        });
+    }
+
+    /// The K closest nodes to `target` from the routing table to seed a
+    /// lookup with. Bad nodes are skipped, they would only time out.
+    fn closest_nodes(&self, target: &H::Id) -> Vec<NodeInfo<H::Id>> {
+        self.nodes
+            .closest_iter(*target)
+            .filter(|contact| !contact.is_bad())
+            .take(K)
+            .map(Contact::node_info)
+            .collect()
+    }
+
+    /// The K closest nodes to `target` from the routing table for an answer
+    /// to `from`, split into IPv4 `nodes` and IPv6 `nodes6`. The requester
+    /// itself and bad nodes are left out.
+    fn closest_compact(&self, target: &H::Id, from: SocketAddr) -> (Nodes<H::Id>, Nodes<H::Id>) {
+        self.nodes
+            .closest_filtered(*target, Some(K), |contact| {
+                contact.addr() != from && !contact.is_bad()
+            })
+            .into_iter()
+            .map(Contact::node_info)
+            .partition(|node| node.addr.is_ipv4())
+    }
+
+    /// Looks up a random id in the range of every bucket that did not change
+    /// for `bucket_outdated_time_span`, to find fresh nodes for it.
+    fn refresh_buckets(&mut self) {
+        for prefix in self.nodes.outdated(self.bucket_outdated_time_span) {
+            let mut target = H::Id::default();
+            prefix.random_id(target.as_mut(), &mut self.rng);
+            let query = Query::FindNode {
+                id: self.node_id,
+                target,
+                want: vec![],
+            };
+            let lookup = Lookup::new(target, self.closest_nodes(&target), vec![]);
+            let rpc = self.rpc.clone();
+            self.tasks.spawn(async move {
+                lookup.run(&rpc, query, |_, _| {}).await;
+                TaskOutput::Done
+            });
+        }
+    }
+
+    fn handle_node_event(&mut self, event: kbucket::Event<Contact<H::Id>>) {
+        match event {
+            kbucket::Event::Added(contact) => {
+                self.addrs.insert(contact.addr(), *contact.id());
+            }
+            kbucket::Event::Removed(contact) => self.forget_addr(&contact),
+            kbucket::Event::Updated { old, new } => {
+                self.forget_addr(&old);
+                self.addrs.insert(new.addr(), *new.id());
+            }
+            kbucket::Event::Ping { old, .. } => {
+                // a ping for this bucket is already in flight
+                if old
+                    .iter()
+                    .any(|contact| self.pinging.contains(contact.id()))
+                {
+                    return;
+                }
+                self.pinging.extend(old.iter().map(|contact| *contact.id()));
+                self.tasks.spawn(ping(self.rpc.clone(), self.node_id, old));
+            }
+        }
+    }
+
+    /// Rebuilds the address index from the routing table, after we missed
+    /// some of its events.
+    fn reindex_addrs(&mut self) {
+        self.addrs = self
+            .nodes
+            .iter()
+            .map(|contact| (contact.addr(), *contact.id()))
+            .collect();
+    }
+
+    /// Drops the address of `contact` from the index, unless another contact
+    /// has taken it over since.
+    fn forget_addr(&mut self, contact: &Contact<H::Id>) {
+        if self.addrs.get(&contact.addr()) == Some(contact.id()) {
+            self.addrs.remove(&contact.addr());
+        }
+    }
+
+    async fn handle_rpc_event(&mut self, event: Event<H::Id>) {
+        match event {
+            Event::Query {
+                from,
+                transaction_id,
+                read_only,
+                query,
+            } => {
+                // someone claiming our own id, not worth an answer
+                if *query.id() == self.node_id {
+                    return;
+                }
+                // read-only nodes don't answer queries, they are never added
+                // to the routing table
+                match self.nodes.get(*query.id()) {
+                    _ if read_only => {}
+                    Some(contact) if contact.addr() == from => {
+                        self.update_contact(*query.id(), from, Contact::queried);
+                    }
+                    _ => self.verify(from),
+                }
+                let result = match self.handle_query(from, *query) {
+                    Ok(response) => self.rpc.respond(from, transaction_id, response).await,
+                    Err(error) => self.rpc.error(from, transaction_id, error).await,
+                };
+                // the requester is gone, nothing else to do
+                result.ok();
+            }
+            Event::Response { from, id } => {
+                self.update_contact(id, from, Contact::responded);
+            }
+            Event::Timeout { to } => {
+                let Some(id) = self.addrs.get(&to).copied() else {
+                    return;
+                };
+                let Some(contact) = self.nodes.get_mut(id) else {
+                    return;
+                };
+                contact.failed();
+                // the freshest replacement of the bucket takes its place
+                if contact.is_bad() {
+                    self.nodes.remove(id);
+                }
+            }
+        }
+    }
+
+    /// Pings a node that sent us a query but is not in the routing table.
+    /// Anyone can send a query with any id, so the node is only added once
+    /// it responds, like every node that responds to us.
+    ///
+    /// Nodes are not pinged while `max_verifying` pings are in flight, they
+    /// are tried again with their next query.
+    fn verify(&mut self, addr: SocketAddr) {
+        if self.verifying.len() >= self.max_verifying || !self.verifying.insert(addr) {
+            return;
+        }
+        let rpc = self.rpc.clone();
+        let query = Query::Ping { id: self.node_id };
+        self.tasks.spawn(async move {
+            rpc.query(addr, query).await.ok();
+            TaskOutput::Verified(addr)
+        });
+    }
+
+    /// Adds the node to the routing table, or moves it to the end of its
+    /// bucket as the most recently seen, keeping what we know about it.
+    fn update_contact(&mut self, id: H::Id, addr: SocketAddr, update: fn(&mut Contact<H::Id>)) {
+        if id == self.node_id {
+            return;
+        }
+        let mut contact = match self.nodes.get(id) {
+            Some(contact) if contact.addr() == addr => contact.clone(),
+            _ => Contact::new(id, addr),
+        };
+        update(&mut contact);
+        self.nodes.add(contact);
+    }
+
+    fn handle_query(
+        &mut self,
+        from: SocketAddr,
+        query: Query<H::Id>,
+    ) -> Result<Response<H::Id>, ErrorMessage> {
+        let mut response = Response {
+            id: self.node_id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping { .. } => {}
+            Query::FindNode { target, .. } => {
+                (response.nodes, response.nodes6) = self.closest_compact(&target, from);
+            }
+            Query::GetPeers { info_hash, .. } => {
+                response.values = self.peers.get(&info_hash, &mut self.rng);
+                if response.values.is_empty() {
+                    (response.nodes, response.nodes6) = self.closest_compact(&info_hash, from);
+                }
+                response.token = Some(self.secrets.token(from.ip()));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                if !self.secrets.verify(from.ip(), &token) {
+                    return Err(ErrorMessage {
+                        code: ErrorMessage::PROTOCOL,
+                        message: "bad token".into(),
+                    });
+                }
+                let port = if implied_port { from.port() } else { port };
+                self.peers.add(info_hash, SocketAddr::new(from.ip(), port));
+            }
+            Query::Get { .. } | Query::Put { .. } => {
+                return Err(ErrorMessage {
+                    code: ErrorMessage::METHOD_UNKNOWN,
+                    message: "method unknown".into(),
+                });
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Looks up our own id, starting from `seeds` and the bootstrap nodes, to
+/// fill the routing table. Every node that responds is added by the actor,
+/// and counted in `bootstrapped`.
+async fn bootstrap<I: Id>(
+    rpc: Rpc<I>,
+    node_id: I,
+    seeds: Vec<NodeInfo<I>>,
+    urls: Vec<Url>,
+    bootstrapped: watch::Sender<Option<usize>>,
+) {
+    let mut routers = Vec::new();
+    for url in &urls {
+        // unresolvable bootstrap nodes are skipped
+        if let Ok(addrs) = resolve(url).await {
+            routers.extend(addrs);
+        }
+    }
+
+    let query = Query::FindNode {
+        id: node_id,
+        target: node_id,
+        want: vec![],
+    };
+    let mut found = 0;
+    Lookup::new(node_id, seeds, routers)
+        .run(&rpc, query, |_, _| found += 1)
+        .await;
+
+    bootstrapped.send_replace(Some(found));
+}
+
+/// Pings the least recently seen contacts `old` of a full bucket, to find
+/// out which ones can make room for a new contact. Contacts that fail to
+/// respond twice in a row are bad, and reported as dead.
+async fn ping<I: Id>(rpc: Rpc<I>, node_id: I, old: Vec<Contact<I>>) -> TaskOutput<I> {
+    let mut pings = JoinSet::new();
+    for contact in &old {
+        let rpc = rpc.clone();
+        let (id, addr) = (*contact.id(), contact.addr());
+        pings.spawn(async move {
+            for _ in 0..2 {
+                if rpc.query(addr, Query::Ping { id: node_id }).await.is_ok() {
+                    return (id, true);
+                }
+            }
+            (id, false)
+        });
+    }
+
+    let mut dead = Vec::new();
+    while let Some(result) = pings.join_next().await {
+        if let Ok((id, false)) = result {
+            dead.push(id);
+        }
+    }
+
+    TaskOutput::Pinged {
+        pinged: old.iter().map(|contact| *contact.id()).collect(),
+        dead,
+    }
+}
+
+/// Runs a get_peers lookup for `info_hash`, and sends every new peer that
+/// is found to `peers`. Stops early if the receiver is dropped.
+///
+/// Returns the table of the lookup, unless it stopped early.
+async fn get_peers<I: Id>(
+    rpc: Rpc<I>,
+    node_id: I,
+    info_hash: I,
+    seeds: Vec<NodeInfo<I>>,
+    peers: mpsc::UnboundedSender<SocketAddr>,
+) -> TaskOutput<I> {
+    let query = Query::GetPeers {
+        id: node_id,
+        info_hash,
+        want: vec![],
+    };
+    let mut seen = HashSet::new();
+    let lookup = Lookup::new(info_hash, seeds, vec![]).run(&rpc, query, |_, response| {
+        for peer in &response.values {
+            if seen.insert(*peer) {
+                peers.send(*peer).ok();
+            }
+        }
+    });
+
+    tokio::select! {
+        responses = lookup => TaskOutput::Table(info_hash, lookup_table(info_hash, responses)),
+        _ = peers.closed() => TaskOutput::Done,
+    }
+}
+
+struct Announce<I> {
+    info_hash: I,
+    port: u16,
+    implied_port: bool,
+}
+
+/// Sends announce_peer to the `K` closest nodes with a token, taken from
+/// `cached` or else from a new get_peers lookup, and sends the nodes that
+/// accepted to `s`.
+///
+/// Returns the table of the lookup, if one was needed.
+async fn announce<I: Id>(
+    rpc: Rpc<I>,
+    node_id: I,
+    announce: Announce<I>,
+    seeds: Vec<NodeInfo<I>>,
+    cached: Option<Vec<Contact<I>>>,
+    s: oneshot::Sender<Vec<SocketAddr>>,
+) -> TaskOutput<I> {
+    let info_hash = announce.info_hash;
+    let (contacts, table) = match cached {
+        Some(contacts) => (contacts, None),
+        None => {
+            let query = Query::GetPeers {
+                id: node_id,
+                info_hash,
+                want: vec![],
+            };
+            let responses = Lookup::new(info_hash, seeds, vec![])
+                .run(&rpc, query, |_, _| {})
+                .await;
+            let table = lookup_table(info_hash, responses);
+            let contacts = table
+                .closest(info_hash, Some(K))
+                .into_iter()
+                .cloned()
+                .collect();
+            (contacts, Some(table))
+        }
+    };
+
+    let mut queries = JoinSet::new();
+    for contact in contacts {
+        let Some(token) = contact.token() else {
+            continue;
+        };
+        let query = Query::AnnouncePeer {
+            id: node_id,
+            info_hash,
+            port: announce.port,
+            implied_port: announce.implied_port,
+            token: token.to_vec(),
+        };
+        let rpc = rpc.clone();
+        let addr = contact.addr();
+        queries.spawn(async move { (addr, rpc.query(addr, query).await) });
+    }
+
+    let mut accepted = Vec::new();
+    while let Some(result) = queries.join_next().await {
+        if let Ok((addr, Ok(_))) = result {
+            accepted.push(addr);
+        }
+    }
+    s.send(accepted).ok();
+
+    match table {
+        Some(table) => TaskOutput::Table(info_hash, table),
+        None => TaskOutput::Done,
+    }
 }
 
-struct Secrets {
-    a: [u8; HASH_LENGTH],
-    b: [u8; HASH_LENGTH],
+/// Builds the table of a get_peers lookup from the nodes that responded
+/// with a token.
+fn lookup_table<I: Id>(target: I, responses: Vec<(NodeInfo<I>, Response<I>)>) -> Table<I> {
+    let mut table = Table::new(target, Some(K), None);
+    for (node, response) in responses {
+        if let Some(token) = response.token {
+            table.add(Contact::new(node.id, node.addr).with_token(token));
+        }
+    }
+    table
 }
 
-impl Secrets {
+async fn resolve(url: &Url) -> Result<Vec<SocketAddr>> {
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+    let addrs = match url.host().context("url without host")? {
+        Host::Ipv4(ip) => vec![SocketAddr::from((ip, port))],
+        Host::Ipv6(ip) => vec![SocketAddr::from((ip, port))],
+        Host::Domain(domain) => tokio::net::lookup_host((domain, port)).await?.collect(),
+    };
+    Ok(addrs)
+}
+
+/// Secrets for announce tokens. `a` is the current secret, `b` the previous
+/// one, so a token stays valid for at least one `ROTATE_INTERVAL`.
+struct Secrets<H: Hash> {
+    a: H::Id,
+    b: H::Id,
+}
+
+impl<H: Hash> Secrets<H> {
     fn new<R: RngCore>(rng: &mut R) -> Self {
-        let mut s = Secrets {
-            a: [0u8; HASH_LENGTH],
-            b: [0u8; HASH_LENGTH],
+        let mut s = Self {
+            a: H::Id::default(),
+            b: H::Id::default(),
         };
-        rng.fill_bytes(&mut s.a);
-        rng.fill_bytes(&mut s.b);
+        rng.fill_bytes(s.a.as_mut());
+        rng.fill_bytes(s.b.as_mut());
         s
     }
 
     fn rotate<R: RngCore>(&mut self, rng: &mut R) {
         std::mem::swap(&mut self.a, &mut self.b);
-        rng.fill_bytes(&mut self.a);
+        rng.fill_bytes(self.a.as_mut());
+    }
+
+    /// Token for a get_peers response to `ip`.
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        token::<H>(ip, self.a.as_ref())
+    }
+
+    /// Whether `token` was issued to `ip` with the current or the previous
+    /// secret.
+    fn verify(&self, ip: IpAddr, token: &[u8]) -> bool {
+        token == self::token::<H>(ip, self.a.as_ref())
+            || token == self::token::<H>(ip, self.b.as_ref())
     }
 }
 
+/// Hash of the IP and the secret.
+fn token<H: Hash>(ip: IpAddr, secret: &[u8]) -> Vec<u8> {
+    let id = match ip {
+        IpAddr::V4(ip) => H::digest(&[&ip.octets(), secret]),
+        IpAddr::V6(ip) => H::digest(&[&ip.octets(), secret]),
+    };
+    id.as_ref().to_vec()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use futures::StreamExt;
+
     use super::*;
+    use crate::rpc::{Body, ErrorMessage, Message, Response};
+
+    type NodeId = <Sha1 as Hash>::Id;
+
+    fn test_opts<H: Hash>(bootstrap: &[SocketAddr]) -> Opts<H> {
+        Opts {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: bootstrap
+                .iter()
+                .map(|addr| Url::parse(&format!("udp://{addr}")).unwrap())
+                .collect(),
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        }
+    }
+
+    /// Stand-in for a bootstrap router or a remote node.
+    #[derive(Clone, Default)]
+    struct StandIn {
+        id: NodeId,
+        /// Returned in every response
+        nodes: Vec<NodeInfo>,
+        /// Returned in every response
+        values: Vec<SocketAddr>,
+        /// Returned in every response, and required to accept announce_peer
+        token: Option<Vec<u8>>,
+        /// Stops answering once set
+        dead: Arc<AtomicBool>,
+    }
+
+    impl StandIn {
+        fn new(id: u8) -> Self {
+            StandIn {
+                id: [id; NodeId::LEN],
+                ..Default::default()
+            }
+        }
+
+        async fn spawn(self) -> NodeInfo {
+            self.spawn_on(UdpSocket::bind("127.0.0.1:0").await.unwrap())
+                .await
+        }
+
+        async fn spawn_on(self, socket: UdpSocket) -> NodeInfo {
+            self.start(socket, None).await.0
+        }
+
+        /// Starts with a ping to `dht`, to get into its routing table.
+        async fn join(self, dht: &Dht) -> NodeInfo {
+            self.join_with_rpc(dht).await.0
+        }
+
+        /// Like [`StandIn::join`], and returns the rpc of the node to send
+        /// queries from its address.
+        async fn join_with_rpc(self, dht: &Dht) -> (NodeInfo, Rpc) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            self.start(socket, Some(dht.local_addr())).await
+        }
+
+        async fn start(self, socket: UdpSocket, join: Option<SocketAddr>) -> (NodeInfo, Rpc) {
+            let (rpc, mut events) = Rpc::new(socket, Duration::from_secs(1), 16);
+            let node = NodeInfo {
+                id: self.id,
+                addr: rpc.local_addr().unwrap(),
+            };
+            if let Some(addr) = join {
+                rpc.query(addr, Query::Ping { id: self.id }).await.unwrap();
+            }
+            let client = rpc.clone();
+            tokio::task::spawn(async move {
+                while let Some(event) = events.recv().await {
+                    if self.dead.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    if let Event::Query {
+                        from,
+                        transaction_id,
+                        query,
+                        ..
+                    } = event
+                    {
+                        if let Query::AnnouncePeer { token, .. } = *query {
+                            if self.token.as_ref() != Some(&token) {
+                                let error = ErrorMessage {
+                                    code: ErrorMessage::PROTOCOL,
+                                    message: "bad token".into(),
+                                };
+                                rpc.error(from, transaction_id, error).await.ok();
+                                continue;
+                            }
+                        }
+                        let response = Response {
+                            id: self.id,
+                            nodes: self.nodes.clone(),
+                            values: self.values.clone(),
+                            token: self.token.clone(),
+                            ..Default::default()
+                        };
+                        rpc.respond(from, transaction_id, response).await.ok();
+                    }
+                }
+            });
+            (node, client)
+        }
+    }
+
+    /// Waits for the actor to process the responses it got so far.
+    async fn wait_for_routing_table_len<H: Hash>(dht: &Dht<H>, len: usize) {
+        for _ in 0..100 {
+            if dht.routing_table_len().await.unwrap() == len {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(dht.routing_table_len().await.unwrap(), len);
+    }
+
+    #[test]
+    fn test_token() {
+        let mut rng = rand::rngs::OsRng;
+        let secrets = Secrets::<Sha1>::new(&mut rng);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let token = secrets.token(ip);
+        assert_eq!(token.len(), 20);
+        assert_eq!(Secrets::<Sha256>::new(&mut rng).token(ip).len(), 32);
+        assert!(secrets.verify(ip, &token));
+        // bound to the requester's ip
+        assert!(!secrets.verify(IpAddr::from([10, 0, 0, 2]), &token));
+        assert!(!secrets.verify("::ffff:10.0.0.1".parse().unwrap(), &token));
+        assert!(!secrets.verify(ip, b"garbage"));
+    }
+
+    #[test]
+    fn test_token_rotation() {
+        let mut rng = rand::rngs::OsRng;
+        let mut secrets = Secrets::<Sha1>::new(&mut rng);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let old = secrets.token(ip);
+
+        // still valid with the previous secret
+        secrets.rotate(&mut rng);
+        assert!(secrets.verify(ip, &old));
+        let current = secrets.token(ip);
+        assert_ne!(current, old);
+        assert!(secrets.verify(ip, &current));
+
+        // expired after the next rotation
+        secrets.rotate(&mut rng);
+        assert!(!secrets.verify(ip, &old));
+        assert!(secrets.verify(ip, &current));
+    }
+
+    #[test]
+    fn test_default_bootstrap() {
+        let opts = Opts::<Sha1>::default();
+        assert_eq!(opts.bootstrap.len(), 3);
+        assert_eq!(opts.bootstrap[0].host_str(), Some("router.bittorrent.com"));
+        // a private network must not reach out to the BitTorrent routers
+        assert!(Opts::<Sha256>::default().bootstrap.is_empty());
+    }
 
     #[tokio::test]
     async fn test_startup() {
-        let rng = rand::rngs::OsRng::default();
-        let dht = Dht::new(Opts::default(), rng).await.unwrap();
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_addr() {
+        let opts = Opts {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: vec![],
+            ..Default::default()
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        let addr = dht.local_addr();
+        assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(addr.port(), 0);
+        // the port is taken
+        assert!(std::net::UdpSocket::bind(addr).is_err());
+        dht.shutdown().await.unwrap();
+
+        let opts = Opts {
+            bind_addr: "[::1]:0".parse().unwrap(),
+            bootstrap: vec![],
+            ..Default::default()
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        assert!(dht.local_addr().is_ipv6());
+        assert_ne!(dht.local_addr().port(), 0);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pre_bound_socket() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let opts = Opts {
+            socket: Some(socket),
+            bootstrap: vec![],
+            ..Default::default()
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        assert_eq!(dht.local_addr(), addr);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rpc_stats() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        let stats = dht.rpc_stats().await.unwrap();
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.queued, 0);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap() {
+        let mut nodes = Vec::new();
+        for i in 1..=3u8 {
+            nodes.push(StandIn::new(i).spawn().await);
+        }
+        let router = StandIn {
+            nodes,
+            ..StandIn::new(0xff)
+        }
+        .spawn()
+        .await
+        .addr;
+
+        let dht = Dht::new(test_opts(&[router]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        let found = tokio::time::timeout(Duration::from_secs(5), dht.bootstrapped())
+            .await
+            .unwrap()
+            .unwrap();
+        // the router and the three nodes it knows
+        assert_eq!(found, 4);
+        wait_for_routing_table_len(&dht, 4).await;
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_without_routers() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        let found = tokio::time::timeout(Duration::from_secs(1), dht.bootstrapped())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, 0);
+        assert_eq!(dht.routing_table_len().await.unwrap(), 0);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_unreachable_router() {
+        // bound, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dht = Dht::new(
+            test_opts(&[silent.local_addr().unwrap()]),
+            rand::rngs::OsRng,
+        )
+        .await
+        .unwrap();
+        let found = tokio::time::timeout(Duration::from_secs(5), dht.bootstrapped())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, 0);
+        assert_eq!(dht.routing_table_len().await.unwrap(), 0);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_retries_while_table_is_empty() {
+        // silent during the first attempt, as if we were offline
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let opts = Opts {
+            time_bucket_outdated: Duration::from_millis(100),
+            ..test_opts(&[socket.local_addr().unwrap()])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        assert_eq!(dht.bootstrapped().await.unwrap(), 0);
+
+        let node = StandIn::new(1).spawn().await;
+        StandIn {
+            nodes: vec![node],
+            ..StandIn::new(0xff)
+        }
+        .spawn_on(socket)
+        .await;
+        wait_for_routing_table_len(&dht, 2).await;
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_peers() {
+        let peer = |port| SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), port));
+        let a = StandIn {
+            values: vec![peer(1), peer(2)],
+            ..StandIn::new(1)
+        }
+        .spawn()
+        .await;
+        let b = StandIn {
+            nodes: vec![a],
+            values: vec![peer(2), peer(3)],
+            ..StandIn::new(2)
+        }
+        .spawn()
+        .await;
+        let router = StandIn {
+            nodes: vec![a, b],
+            ..StandIn::new(0xff)
+        }
+        .spawn()
+        .await
+        .addr;
+
+        let dht = Dht::new(test_opts(&[router]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        dht.bootstrapped().await.unwrap();
+        wait_for_routing_table_len(&dht, 3).await;
+
+        let peers = dht.get_peers([3; NodeId::LEN]).await.unwrap();
+        let mut peers: Vec<_> = tokio::time::timeout(Duration::from_secs(5), peers.collect())
+            .await
+            .unwrap();
+        peers.sort();
+        assert_eq!(peers, vec![peer(1), peer(2), peer(3)]);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sha256() {
+        let a = Dht::<Sha256>::with_hash(test_opts(&[]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        let b = Dht::<Sha256>::with_hash(test_opts(&[a.local_addr()]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        b.bootstrapped().await.unwrap();
+        wait_for_routing_table_len(&b, 1).await;
+        wait_for_routing_table_len(&a, 1).await;
+        assert_eq!(b.buckets().await.unwrap()[0].nodes[0].0.len(), 32);
+
+        let info_hash = [7; 32];
+        let accepted = b.announce(info_hash, 1000, false).await.unwrap();
+        assert_eq!(accepted, vec![a.local_addr()]);
+        let peers: Vec<_> = b.get_peers(info_hash).await.unwrap().collect().await;
+        assert_eq!(peers, vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 1000))]);
+
+        b.shutdown().await.unwrap();
+        a.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let a = StandIn {
+            token: Some(b"a".to_vec()),
+            ..StandIn::new(1)
+        }
+        .spawn()
+        .await;
+        let b = StandIn {
+            token: Some(b"b".to_vec()),
+            ..StandIn::new(2)
+        }
+        .spawn()
+        .await;
+        // gives no token, so we can't announce to it
+        let c = StandIn::new(3).spawn().await;
+        let router = StandIn {
+            nodes: vec![a, b, c],
+            ..StandIn::new(0xff)
+        }
+        .spawn()
+        .await
+        .addr;
+
+        let dht = Dht::new(test_opts(&[router]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        dht.bootstrapped().await.unwrap();
+        wait_for_routing_table_len(&dht, 4).await;
+
+        let mut accepted = dht.announce([4; NodeId::LEN], 6881, false).await.unwrap();
+        accepted.sort();
+        let mut expected = vec![a.addr, b.addr];
+        expected.sort();
+        assert_eq!(accepted, expected);
+
+        // again with the tokens from the cached table
+        let mut accepted = dht.announce([4; NodeId::LEN], 6881, true).await.unwrap();
+        accepted.sort();
+        assert_eq!(accepted, expected);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_announce_after_empty_lookup() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        // nobody to ask yet
+        let info_hash = [4; NodeId::LEN];
+        let peers: Vec<_> = dht.get_peers(info_hash).await.unwrap().collect().await;
+        assert!(peers.is_empty());
+
+        // the empty table of that lookup is not used
+        let a = StandIn {
+            token: Some(b"a".to_vec()),
+            ..StandIn::new(1)
+        }
+        .join(&dht)
+        .await;
+        wait_for_routing_table_len(&dht, 1).await;
+        let accepted = dht.announce(info_hash, 6881, false).await.unwrap();
+        assert_eq!(accepted, vec![a.addr]);
+        dht.shutdown().await.unwrap();
+    }
+
+    /// A client querying the node under test.
+    async fn client() -> Rpc {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Rpc::new(socket, Duration::from_secs(1), 16).0
+    }
+
+    #[tokio::test]
+    async fn test_answer_ping_and_find_node() {
+        let a = StandIn::new(1).spawn().await;
+        let router = StandIn {
+            nodes: vec![a],
+            ..StandIn::new(0xff)
+        }
+        .spawn()
+        .await;
+        let opts = Opts {
+            node_id: Some([0x42; NodeId::LEN]),
+            ..test_opts(&[router.addr])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+        wait_for_routing_table_len(&dht, 2).await;
+
+        let client = client().await;
+        let id = [0x43; NodeId::LEN];
+        let response = client
+            .query(dht.local_addr(), Query::Ping { id })
+            .await
+            .unwrap();
+        assert_eq!(response.id, [0x42; NodeId::LEN]);
+
+        let response = client
+            .query(
+                dht.local_addr(),
+                Query::FindNode {
+                    id,
+                    target: [1; NodeId::LEN],
+                    want: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.id, [0x42; NodeId::LEN]);
+        assert_eq!(response.nodes[0], a);
+        assert!(response.nodes.contains(&router));
+        assert!(response.nodes6.is_empty());
+
+        // the client never answers our ping, so it is not added
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(dht.routing_table_len().await.unwrap(), 2);
+
+        // a node in the routing table is left out of answers to itself
+        let (node, rpc) = StandIn::new(0x44).join_with_rpc(&dht).await;
+        wait_for_routing_table_len(&dht, 3).await;
+        let find_node = |id| Query::FindNode {
+            id,
+            target: node.id,
+            want: vec![],
+        };
+        let response = client.query(dht.local_addr(), find_node(id)).await.unwrap();
+        assert_eq!(response.nodes[0], node);
+        let response = rpc
+            .query(dht.local_addr(), find_node(node.id))
+            .await
+            .unwrap();
+        assert_eq!(response.nodes.len(), 2);
+        assert!(!response.nodes.contains(&node));
+        let response = rpc
+            .query(
+                dht.local_addr(),
+                Query::GetPeers {
+                    id: node.id,
+                    info_hash: node.id,
+                    want: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.nodes.len(), 2);
+        assert!(!response.nodes.contains(&node));
+
+        // queries with our own id are ignored
+        let error = client
+            .query(
+                dht.local_addr(),
+                Query::Ping {
+                    id: [0x42; NodeId::LEN],
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_querier_is_not_verified() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let message = Message {
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            requester_ip: None,
+            read_only: true,
+            body: Body::Query(Query::Ping {
+                id: [0x43; NodeId::LEN],
+            }),
+        };
+        socket
+            .send_to(&message.encode(), dht.local_addr())
+            .await
+            .unwrap();
+
+        // answered, but not pinged back
+        let mut buf = [0u8; 1500];
+        let (len, _) = socket.recv_from(&mut buf).await.unwrap();
+        let response = <Message>::decode(&buf[..len]).unwrap();
+        assert!(matches!(response.body, Body::Response(_)));
+        let ping = tokio::time::timeout(Duration::from_millis(300), socket.recv_from(&mut buf));
+        assert!(ping.await.is_err());
+        assert_eq!(dht.routing_table_len().await.unwrap(), 0);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lookup_during_query_flood() {
+        let opts = Opts {
+            timeout: Duration::from_secs(1),
+            ..test_opts(&[])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+        let peer = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 1000));
+        StandIn {
+            values: vec![peer],
+            ..StandIn::new(1)
+        }
+        .join(&dht)
+        .await;
+        wait_for_routing_table_len(&dht, 1).await;
+
+        // queries from many addresses that never answer our pings, enough to
+        // keep all query slots busy for seconds if each one was verified
+        let mut flood = Vec::new();
+        for i in 0..64u8 {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let message = Message {
+                transaction_id: vec![i],
+                version: None,
+                requester_ip: None,
+                read_only: false,
+                body: Body::Query(Query::Ping {
+                    id: [i; NodeId::LEN],
+                }),
+            };
+            socket
+                .send_to(&message.encode(), dht.local_addr())
+                .await
+                .unwrap();
+            flood.push(socket);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let peers = dht.get_peers([2; NodeId::LEN]).await.unwrap();
+        let peers: Vec<_> = tokio::time::timeout(Duration::from_millis(500), peers.collect())
+            .await
+            .unwrap();
+        assert_eq!(peers, vec![peer]);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spoofed_id_does_not_replace_node() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        let real = StandIn::new(0x43).join(&dht).await;
+        wait_for_routing_table_len(&dht, 1).await;
+        // answers our ping as well
+        StandIn::new(0x43).join(&dht).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let buckets = dht.buckets().await.unwrap();
+        assert_eq!(buckets[0].nodes, vec![(real.id, real.addr)]);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_answer_get_peers_and_announce_peer() {
+        let a = StandIn::new(1).spawn().await;
+        let router = StandIn {
+            nodes: vec![a],
+            ..StandIn::new(0xff)
+        }
+        .spawn()
+        .await;
+        let dht = Dht::new(test_opts(&[router.addr]), rand::rngs::OsRng)
+            .await
+            .unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        let client = client().await;
+        let id = [0x43; NodeId::LEN];
+        let info_hash = [2; NodeId::LEN];
+        let get_peers = Query::GetPeers {
+            id,
+            info_hash,
+            want: vec![],
+        };
+
+        // no peers yet, closest nodes instead
+        let response = client
+            .query(dht.local_addr(), get_peers.clone())
+            .await
+            .unwrap();
+        assert!(response.values.is_empty());
+        assert!(response.nodes.contains(&a));
+        let token = response.token.unwrap();
+
+        let announce = |token: &[u8], port, implied_port| Query::AnnouncePeer {
+            id,
+            info_hash,
+            port,
+            implied_port,
+            token: token.to_vec(),
+        };
+        let error = client
+            .query(dht.local_addr(), announce(b"bad", 1000, false))
+            .await
+            .unwrap_err();
+        let error = error.downcast::<ErrorMessage>().unwrap();
+        assert_eq!(error.code, ErrorMessage::PROTOCOL);
+
+        client
+            .query(dht.local_addr(), announce(&token, 1000, false))
+            .await
+            .unwrap();
+        client
+            .query(dht.local_addr(), announce(&token, 1000, true))
+            .await
+            .unwrap();
+
+        let response = client.query(dht.local_addr(), get_peers).await.unwrap();
+        let mut values = response.values;
+        values.sort();
+        let mut expected = vec![
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 1000)),
+            client.local_addr().unwrap(),
+        ];
+        expected.sort();
+        assert_eq!(values, expected);
+        assert!(response.nodes.is_empty());
+        assert!(response.token.is_some());
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_announce_host() {
+        let opts = Opts {
+            host: Some(Url::parse("udp://10.0.0.1").unwrap()),
+            ..test_opts(&[])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        // no other nodes to announce to, but our own records
+        let accepted = dht.announce([2; NodeId::LEN], 1000, false).await.unwrap();
+        assert!(accepted.is_empty());
+        dht.announce([3; NodeId::LEN], 1000, true).await.unwrap();
+
+        let client = client().await;
+        let get_peers = |info_hash| Query::GetPeers {
+            id: [0x43; NodeId::LEN],
+            info_hash,
+            want: vec![],
+        };
+        let response = client
+            .query(dht.local_addr(), get_peers([2; NodeId::LEN]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.values,
+            vec![SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 1000))]
+        );
+        let response = client
+            .query(dht.local_addr(), get_peers([3; NodeId::LEN]))
+            .await
+            .unwrap();
+        let port = dht.local_addr().port();
+        assert_eq!(
+            response.values,
+            vec![SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), port))]
+        );
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_and_evict() {
+        let opts = Opts {
+            node_id: Some([0; NodeId::LEN]),
+            ..test_opts(&[])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        // fill the bucket far away from our id, which may not be split
+        let far = |i| {
+            let mut id = [0x80; NodeId::LEN];
+            id[NodeId::LEN - 1] = i;
+            StandIn {
+                id,
+                ..Default::default()
+            }
+        };
+        let mut nodes = Vec::new();
+        let mut dead = Vec::new();
+        for i in 0..K as u8 {
+            let node = far(i);
+            if i == 0 || i == 2 {
+                dead.push(node.dead.clone());
+            }
+            nodes.push(node.join(&dht).await);
+        }
+        wait_for_routing_table_len(&dht, K).await;
+
+        // two of them die, and turn questionable when a lookup fails to
+        // reach them, the others stay good
+        for dead in dead {
+            dead.store(true, Ordering::SeqCst);
+        }
+        let peers = dht.get_peers(nodes[0].id).await.unwrap();
+        peers.collect::<Vec<_>>().await;
+
+        // only the questionable ones are pinged
+        let new = far(K as u8).join(&dht).await;
+        wait_for_routing_table_len(&dht, K - 1).await;
+
+        let client = client().await;
+        let response = client
+            .query(
+                dht.local_addr(),
+                Query::FindNode {
+                    id: [0x01; NodeId::LEN],
+                    target: nodes[0].id,
+                    want: vec![],
+                },
+            )
+            .await
+            .unwrap();
+        assert!(response.nodes.contains(&new));
+        assert!(response.nodes.contains(&nodes[1]));
+        assert!(!response.nodes.contains(&nodes[0]));
+        assert!(!response.nodes.contains(&nodes[2]));
+
+        // the full bucket far away, the near one stays empty: the client
+        // never answered our ping
+        let buckets = dht.buckets().await.unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].prefix, "0");
+        assert!(!buckets[0].dont_split);
+        assert!(buckets[0].nodes.is_empty());
+        assert_eq!(buckets[1].prefix, "1");
+        assert_eq!(buckets[1].depth, 1);
+        assert!(buckets[1].dont_split);
+        assert_eq!(buckets[1].nodes.len(), K - 1);
+        assert_eq!(buckets[1].nodes.last(), Some(&(new.id, new.addr)));
+
+        let nodes: Vec<_> = buckets
+            .into_iter()
+            .flat_map(|bucket| bucket.nodes)
+            .collect();
+        assert_eq!(dht.nodes().await.unwrap(), nodes);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_routing_events() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        let mut events = dht.routing_events().await.unwrap();
+
+        let node = StandIn::new(1).join(&dht).await;
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap();
+        assert_eq!(event, Some(Ok(RoutingEvent::Added((node.id, node.addr)))));
+
+        dht.shutdown().await.unwrap();
+        assert_eq!(events.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_failing_node_is_removed() {
+        let dht = Dht::new(test_opts(&[]), rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+        let node = StandIn::new(1);
+        let dead = node.dead.clone();
+        node.join(&dht).await;
+        wait_for_routing_table_len(&dht, 1).await;
+
+        // every lookup queries it once, the second failure makes it bad
+        dead.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            let peers = dht.get_peers([2; NodeId::LEN]).await.unwrap();
+            peers.collect::<Vec<_>>().await;
+        }
+        wait_for_routing_table_len(&dht, 0).await;
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_outdated_buckets() {
+        let opts = Opts {
+            time_bucket_outdated: Duration::from_millis(100),
+            ..test_opts(&[])
+        };
+        let dht = Dht::new(opts, rand::rngs::OsRng).await.unwrap();
+        dht.bootstrapped().await.unwrap();
+
+        // only known to the node that joins
+        let b = StandIn::new(2).spawn().await;
+        StandIn {
+            nodes: vec![b],
+            ..StandIn::new(1)
+        }
+        .join(&dht)
+        .await;
+        wait_for_routing_table_len(&dht, 1).await;
+
+        // the quiet bucket gets refreshed with a lookup through that node
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        wait_for_routing_table_len(&dht, 2).await;
         dht.shutdown().await.unwrap();
     }
 }
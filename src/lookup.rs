@@ -0,0 +1,325 @@
+//! Iterative Kademlia lookup.
+//!
+//! Starts from the closest nodes we know of and repeatedly queries the
+//! closest not yet queried nodes, `ALPHA` at a time, learning about closer
+//! nodes from each response. The lookup converges once the `K` closest nodes
+//! it found have all responded.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use tokio::task::JoinSet;
+
+use crate::hash::Id;
+use crate::kbucket::Distance;
+use crate::rpc::{NodeInfo, Query, Response, Rpc};
+use crate::K;
+
+/// Number of queries in flight per lookup.
+pub const ALPHA: usize = 3;
+
+pub struct Lookup<I = [u8; 20]> {
+    target: I,
+    /// All nodes we heard of, by distance to the target.
+    shortlist: BTreeMap<Distance<I>, Candidate<I>>,
+    /// Nodes without a known id, e.g. bootstrap routers. They are queried
+    /// first, but never part of the result.
+    routers: Vec<SocketAddr>,
+}
+
+struct Candidate<I> {
+    node: NodeInfo<I>,
+    state: State<I>,
+}
+
+enum State<I> {
+    NotQueried,
+    InFlight,
+    Responded(Box<Response<I>>),
+    Failed,
+}
+
+impl<I: Id> Lookup<I> {
+    pub fn new(
+        target: I,
+        seeds: impl IntoIterator<Item = NodeInfo<I>>,
+        routers: Vec<SocketAddr>,
+    ) -> Self {
+        let mut lookup = Lookup {
+            target,
+            shortlist: BTreeMap::new(),
+            routers,
+        };
+        for node in seeds {
+            lookup.insert(node);
+        }
+        lookup
+    }
+
+    fn insert(&mut self, node: NodeInfo<I>) {
+        self.shortlist
+            .entry(Distance::between(&node.id, &self.target))
+            .or_insert(Candidate {
+                node,
+                state: State::NotQueried,
+            });
+    }
+
+    /// The `K` closest nodes that did not fail, closest first.
+    fn closest_mut(&mut self) -> impl Iterator<Item = (&Distance<I>, &mut Candidate<I>)> {
+        self.shortlist
+            .iter_mut()
+            .filter(|(_, c)| !matches!(c.state, State::Failed))
+            .take(K)
+    }
+
+    /// Sends `query` to the nodes of the lookup until it converges, and
+    /// returns the `K` closest nodes that responded, closest first.
+    ///
+    /// `on_response` is called for every response as it arrives.
+    pub async fn run<F>(
+        mut self,
+        rpc: &Rpc<I>,
+        query: Query<I>,
+        mut on_response: F,
+    ) -> Vec<(NodeInfo<I>, Response<I>)>
+    where
+        F: FnMut(&NodeInfo<I>, &Response<I>),
+    {
+        let own_id = *query.id();
+        let mut queries = JoinSet::new();
+        let spawn = |queries: &mut JoinSet<_>, key, addr| {
+            let rpc = rpc.clone();
+            let query = query.clone();
+            queries.spawn(async move { (key, addr, rpc.query(addr, query).await) });
+        };
+
+        for addr in std::mem::take(&mut self.routers) {
+            spawn(&mut queries, None, addr);
+        }
+
+        loop {
+            let mut in_flight = queries.len();
+            let mut converged = true;
+            let mut any = false;
+            for (key, candidate) in self.closest_mut() {
+                any = true;
+                match candidate.state {
+                    State::Responded(_) => continue,
+                    State::NotQueried if in_flight < ALPHA => {
+                        candidate.state = State::InFlight;
+                        spawn(&mut queries, Some(*key), candidate.node.addr);
+                        in_flight += 1;
+                    }
+                    _ => {}
+                }
+                converged = false;
+            }
+            if any && converged {
+                break;
+            }
+
+            // nothing in flight and nothing left to query
+            let Some(result) = queries.join_next().await else {
+                break;
+            };
+            let Ok((key, addr, result)) = result else {
+                continue;
+            };
+
+            let state = match result {
+                Ok(response) => {
+                    on_response(
+                        &NodeInfo {
+                            id: response.id,
+                            addr,
+                        },
+                        &response,
+                    );
+                    for node in response.nodes.iter().chain(&response.nodes6) {
+                        if node.id != own_id {
+                            self.insert(*node);
+                        }
+                    }
+                    State::Responded(Box::new(response))
+                }
+                Err(_) => State::Failed,
+            };
+            if let Some(candidate) = key.and_then(|key| self.shortlist.get_mut(&key)) {
+                candidate.state = state;
+            }
+        }
+
+        self.shortlist
+            .into_values()
+            .filter_map(|candidate| match candidate.state {
+                State::Responded(response) => Some((candidate.node, *response)),
+                _ => None,
+            })
+            .take(K)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::hash::{Hash, Sha1};
+    use crate::rpc::Event;
+
+    type NodeId = <Sha1 as Hash>::Id;
+
+    /// A network of stand-in nodes on localhost. Each node knows all other
+    /// nodes and answers find_node with the closest to the target, dead or
+    /// alive, so that the `K` closest alive nodes are always included.
+    struct Network {
+        nodes: Vec<NodeInfo>,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl Network {
+        async fn new(size: u8, dead: &[u8]) -> Self {
+            let mut rpcs = Vec::new();
+            let mut nodes = Vec::new();
+            for i in 0..size {
+                let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let addr = socket.local_addr().unwrap();
+                let (rpc, events) = Rpc::new(socket, Duration::from_secs(1), 16);
+                // spread the ids over the whole id space
+                let mut id = [i.wrapping_mul(97); NodeId::LEN];
+                id[NodeId::LEN - 1] = i;
+                nodes.push(NodeInfo { id, addr });
+                rpcs.push((rpc, events));
+            }
+
+            let queries = Arc::new(AtomicUsize::new(0));
+            let count = K + dead.len();
+            for (i, (rpc, mut events)) in rpcs.into_iter().enumerate() {
+                if dead.contains(&(i as u8)) {
+                    continue;
+                }
+                let id = nodes[i].id;
+                let nodes = nodes.clone();
+                let queries = queries.clone();
+                tokio::task::spawn(async move {
+                    while let Some(event) = events.recv().await {
+                        let Event::Query {
+                            from,
+                            transaction_id,
+                            query,
+                            ..
+                        } = event
+                        else {
+                            continue;
+                        };
+                        let Query::FindNode { target, .. } = *query else {
+                            continue;
+                        };
+                        queries.fetch_add(1, Ordering::SeqCst);
+                        let response = Response {
+                            id,
+                            nodes: closest(&nodes, &target, count),
+                            ..Default::default()
+                        };
+                        rpc.respond(from, transaction_id, response).await.ok();
+                    }
+                });
+            }
+            Network { nodes, queries }
+        }
+    }
+
+    fn closest(nodes: &[NodeInfo], target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes = nodes.to_vec();
+        nodes.sort_by_key(|node| Distance::between(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    async fn rpc() -> Rpc {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Rpc::new(socket, Duration::from_millis(200), 16).0
+    }
+
+    fn find_node(target: NodeId) -> Query {
+        Query::FindNode {
+            id: [0xfe; NodeId::LEN],
+            target,
+            want: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_finds_k_closest() {
+        let network = Network::new(100, &[]).await;
+        let rpc = rpc().await;
+        let target = [0x42; NodeId::LEN];
+
+        // start from the nodes furthest away
+        let mut seeds = network.nodes.clone();
+        seeds.sort_by_key(|node| std::cmp::Reverse(Distance::between(&node.id, &target)));
+        seeds.truncate(3);
+
+        let mut responses = 0;
+        let lookup = Lookup::new(target, seeds, vec![]);
+        let result = lookup
+            .run(&rpc, find_node(target), |_, _| responses += 1)
+            .await;
+
+        let result: Vec<_> = result.into_iter().map(|(node, _)| node).collect();
+        assert_eq!(result, closest(&network.nodes, &target, K));
+        assert_eq!(responses, network.queries.load(Ordering::SeqCst));
+        // converges without asking everyone
+        assert!(responses < network.nodes.len());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_skips_dead_nodes() {
+        let dead = [0, 1, 2, 50, 51];
+        let network = Network::new(60, &dead).await;
+        let rpc = rpc().await;
+        let target = network.nodes[50].id;
+
+        let lookup = Lookup::new(target, network.nodes[..5].to_vec(), vec![]);
+        let result = lookup.run(&rpc, find_node(target), |_, _| {}).await;
+
+        let alive: Vec<_> = network
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !dead.contains(&(*i as u8)))
+            .map(|(_, node)| *node)
+            .collect();
+        let result: Vec<_> = result.into_iter().map(|(node, _)| node).collect();
+        assert_eq!(result, closest(&alive, &target, K));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_from_routers() {
+        let network = Network::new(30, &[]).await;
+        let rpc = rpc().await;
+        let target = [0x13; NodeId::LEN];
+
+        let lookup = Lookup::new(target, vec![], vec![network.nodes[7].addr]);
+        let result = lookup.run(&rpc, find_node(target), |_, _| {}).await;
+
+        let result: Vec<_> = result.into_iter().map(|(node, _)| node).collect();
+        assert_eq!(result, closest(&network.nodes, &target, K));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_without_nodes() {
+        let rpc = rpc().await;
+        let lookup = Lookup::new([0; NodeId::LEN], vec![], vec![]);
+        let result = lookup
+            .run(&rpc, find_node([0; NodeId::LEN]), |_, _| {})
+            .await;
+        assert!(result.is_empty());
+    }
+}
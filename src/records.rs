@@ -1,13 +1,210 @@
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-// records({
-//       maxAge: opts.maxAge || 0,
-//       maxSize: opts.maxPeers || 10000
-//     })
-pub struct Records {}
+use rand::seq::SliceRandom;
+use rand::Rng;
 
-impl Records {
+use crate::hash::Id;
+
+/// Size budget for the compact `values` of a get_peers response, so that the
+/// whole response fits in one UDP packet, even with the 1280 byte minimum
+/// MTU of IPv6. That is 125 IPv4 or 47 IPv6 peers.
+const MAX_VALUES_SIZE: usize = 1000;
+
+/// Peers announced to us, by info hash.
+///
+/// Holds at most `max_peers` peers over all info hashes, evicting the oldest
+/// announce first, and forgets peers that did not announce again within
+/// `max_age`.
+pub struct Records<I = [u8; 20]> {
+    max_age: Option<Duration>,
+    max_peers: usize,
+    /// Sequence number of the last announce of each peer.
+    peers: HashMap<I, HashMap<SocketAddr, u64>>,
+    /// All announces, oldest first.
+    announces: BTreeMap<u64, Announce<I>>,
+    next_seq: u64,
+}
+
+struct Announce<I> {
+    key: I,
+    peer: SocketAddr,
+    time: Instant,
+}
+
+impl<I: Id> Records<I> {
     pub fn new(max_age: Option<Duration>, max_peers: usize) -> Self {
-        Records {}
+        Records {
+            max_age,
+            max_peers,
+            peers: HashMap::new(),
+            announces: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Adds `peer` for `key`, or refreshes it if it announced before.
+    pub fn add(&mut self, key: I, peer: SocketAddr) {
+        self.expire();
+        if self.max_peers == 0 {
+            return;
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let peers = self.peers.entry(key).or_default();
+        if let Some(old) = peers.insert(peer, seq) {
+            self.announces.remove(&old);
+        }
+        self.announces.insert(
+            seq,
+            Announce {
+                key,
+                peer,
+                time: Instant::now(),
+            },
+        );
+
+        while self.announces.len() > self.max_peers {
+            self.pop_oldest();
+        }
+    }
+
+    /// A random sample of the peers for `key` that fits in one response.
+    pub fn get<R: Rng + ?Sized>(&mut self, key: &I, rng: &mut R) -> Vec<SocketAddr> {
+        self.expire();
+        let Some(peers) = self.peers.get(key) else {
+            return Vec::new();
+        };
+
+        let mut peers: Vec<_> = peers.keys().copied().collect();
+        peers.shuffle(rng);
+        let mut size = 0;
+        peers.retain(|peer| {
+            size += encoded_len(peer);
+            size <= MAX_VALUES_SIZE
+        });
+        peers
+    }
+
+    /// Drops the peers older than `max_age`.
+    fn expire(&mut self) {
+        let Some(max_age) = self.max_age else {
+            return;
+        };
+        while let Some((_, announce)) = self.announces.first_key_value() {
+            if announce.time.elapsed() <= max_age {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        let Some((_, announce)) = self.announces.pop_first() else {
+            return;
+        };
+        if let Some(peers) = self.peers.get_mut(&announce.key) {
+            peers.remove(&announce.peer);
+            if peers.is_empty() {
+                self.peers.remove(&announce.key);
+            }
+        }
+    }
+}
+
+/// Length of a compact peer address as a bencoded string.
+fn encoded_len(peer: &SocketAddr) -> usize {
+    match peer {
+        SocketAddr::V4(_) => "6:".len() + 6,
+        SocketAddr::V6(_) => "18:".len() + 18,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), port))
+    }
+
+    fn sorted(mut peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        peers.sort();
+        peers
+    }
+
+    #[test]
+    fn test_records_dedup() {
+        let mut rng = rand::thread_rng();
+        let mut records = Records::new(None, 10);
+        records.add([1; 20], peer(1));
+        records.add([1; 20], peer(2));
+        records.add([1; 20], peer(1));
+        records.add([2; 20], peer(1));
+
+        assert_eq!(records.announces.len(), 3);
+        assert_eq!(
+            sorted(records.get(&[1; 20], &mut rng)),
+            vec![peer(1), peer(2)]
+        );
+        assert_eq!(records.get(&[2; 20], &mut rng), vec![peer(1)]);
+        assert!(records.get(&[3; 20], &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_records_evict_oldest() {
+        let mut rng = rand::thread_rng();
+        let mut records = Records::new(None, 3);
+        records.add([1; 20], peer(1));
+        records.add([2; 20], peer(2));
+        records.add([1; 20], peer(3));
+        // announcing again makes it the newest
+        records.add([1; 20], peer(1));
+        records.add([2; 20], peer(4));
+
+        assert_eq!(records.announces.len(), 3);
+        assert_eq!(
+            sorted(records.get(&[1; 20], &mut rng)),
+            vec![peer(1), peer(3)]
+        );
+        assert_eq!(records.get(&[2; 20], &mut rng), vec![peer(4)]);
+    }
+
+    #[test]
+    fn test_records_max_age() {
+        let mut rng = rand::thread_rng();
+        let mut records = Records::new(Some(Duration::from_millis(20)), 10);
+        records.add([1; 20], peer(1));
+        std::thread::sleep(Duration::from_millis(30));
+        records.add([1; 20], peer(2));
+
+        assert_eq!(records.get(&[1; 20], &mut rng), vec![peer(2)]);
+        assert_eq!(records.announces.len(), 1);
+    }
+
+    #[test]
+    fn test_records_sample_fits_response() {
+        let mut rng = rand::thread_rng();
+        let mut records = Records::new(None, 1000);
+        for port in 0..200 {
+            records.add([4; 20], peer(port));
+            let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+            records.add([6; 20], SocketAddr::from((ip, port)));
+        }
+
+        let sample = records.get(&[4; 20], &mut rng);
+        assert_eq!(sample.len(), 125);
+        let unique: HashSet<_> = sample.iter().collect();
+        assert_eq!(unique.len(), sample.len());
+        assert_eq!(records.get(&[6; 20], &mut rng).len(), 47);
+
+        // a different sample every time
+        let other = records.get(&[4; 20], &mut rng);
+        assert_ne!(sorted(sample), sorted(other));
     }
 }
@@ -1,8 +1,1550 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Semaphore, SemaphorePermit};
+use tokio::task::JoinHandle;
+
+use crate::bencode::{self, DictEncoder, Encoder, Value};
+use crate::hash::Id;
+
+/// Length of a compact IPv4 address, following the id in a compact node info.
+const COMPACT_ADDR_LEN: usize = 6;
+/// Length of a compact IPv6 address, following the id in a compact node info.
+const COMPACT_ADDR6_LEN: usize = 18;
+
+/// Incoming events that are buffered before packets get dropped.
+const EVENT_QUEUE_LEN: usize = 256;
+
 // krpc(Object.assign({ idLength: this._hashLength }, opts))
-pub struct Rpc {}
+/// KRPC over UDP.
+///
+/// Matches responses to outgoing queries by their transaction id and passes
+/// everything else on as [`Event`]s. Cloning is cheap, all clones share the
+/// same socket.
+pub struct Rpc<I = [u8; 20]> {
+    inner: Arc<Inner<I>>,
+    _recv_task: Arc<RecvTask>,
+}
+
+struct Inner<I> {
+    socket: UdpSocket,
+    timeout: Duration,
+    transactions: Mutex<Transactions<I>>,
+    /// Limits the number of queries in flight, waiting queries are served
+    /// in order.
+    limiter: Semaphore,
+    concurrency: usize,
+    queued: AtomicUsize,
+    waits: Mutex<Waits>,
+    events: mpsc::Sender<Event<I>>,
+}
+
+#[derive(Default)]
+struct Waits {
+    queries: u64,
+    total: Duration,
+    max: Duration,
+}
+
+/// Load of the outgoing query queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RpcStats {
+    /// Queries waiting for one of the in-flight queries to finish.
+    pub queued: usize,
+    /// Queries that have been sent and wait for a response.
+    pub in_flight: usize,
+    /// Number of queries that have been sent so far.
+    pub queries: u64,
+    /// Time all sent queries spent waiting in the queue.
+    pub total_wait: Duration,
+    /// Longest time a single query spent waiting in the queue.
+    pub max_wait: Duration,
+}
+
+impl RpcStats {
+    /// Average time a query spent waiting in the queue.
+    pub fn average_wait(&self) -> Duration {
+        if self.queries == 0 {
+            return Duration::ZERO;
+        }
+        self.total_wait.div_f64(self.queries as f64)
+    }
+}
+
+/// Queries waiting for a response, by transaction id. Ids are random, so
+/// that responses can't be forged by guessing them.
+struct Transactions<I> {
+    pending: HashMap<u16, Pending<I>>,
+}
+
+impl<I> Default for Transactions<I> {
+    fn default() -> Self {
+        Transactions {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+struct Pending<I> {
+    addr: SocketAddr,
+    sender: oneshot::Sender<Result<Response<I>, ErrorMessage>>,
+}
+
+/// Aborts the receive loop once the last [`Rpc`] is dropped.
+struct RecvTask(JoinHandle<()>);
+
+impl Drop for RecvTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Removes a pending transaction, when the query is answered, times out or
+/// is cancelled.
+struct TransactionGuard<'a, I> {
+    inner: &'a Inner<I>,
+    id: u16,
+}
+
+/// Counts a query as queued, until it gets a slot or is cancelled.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<I> Drop for TransactionGuard<'_, I> {
+    fn drop(&mut self) {
+        self.inner
+            .transactions
+            .lock()
+            .unwrap()
+            .pending
+            .remove(&self.id);
+    }
+}
+
+/// Messages received from other nodes, that are not answers to our queries,
+/// and queries that went unanswered.
+#[derive(Debug)]
+pub enum Event<I = [u8; 20]> {
+    /// A query, to be answered with [`Rpc::respond`] or [`Rpc::error`].
+    Query {
+        from: SocketAddr,
+        transaction_id: Vec<u8>,
+        /// The requester does not answer queries itself (BEP 43).
+        read_only: bool,
+        query: Box<Query<I>>,
+    },
+    /// A node responded to one of our queries.
+    Response { from: SocketAddr, id: I },
+    /// A node did not respond to one of our queries in time.
+    Timeout { to: SocketAddr },
+}
+
+impl<I> Clone for Rpc<I> {
+    fn clone(&self) -> Self {
+        Rpc {
+            inner: self.inner.clone(),
+            _recv_task: self._recv_task.clone(),
+        }
+    }
+}
+
+impl<I: Id> Rpc<I> {
+    /// Starts receiving on `socket`. Queries are failed if no response
+    /// arrives within `timeout`, and at most `concurrency` queries are in
+    /// flight at the same time.
+    pub fn new(
+        socket: UdpSocket,
+        timeout: Duration,
+        concurrency: usize,
+    ) -> (Self, mpsc::Receiver<Event<I>>) {
+        let concurrency = concurrency.max(1);
+        let (events, events_receiver) = mpsc::channel(EVENT_QUEUE_LEN);
+        let inner = Arc::new(Inner {
+            socket,
+            timeout,
+            transactions: Default::default(),
+            limiter: Semaphore::new(concurrency),
+            concurrency,
+            queued: AtomicUsize::new(0),
+            waits: Default::default(),
+            events: events.clone(),
+        });
+        let recv_task = tokio::task::spawn(recv_loop(inner.clone(), events));
+
+        let rpc = Rpc {
+            inner,
+            _recv_task: Arc::new(RecvTask(recv_task)),
+        };
+        (rpc, events_receiver)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    pub fn stats(&self) -> RpcStats {
+        let waits = self.inner.waits.lock().unwrap();
+        RpcStats {
+            queued: self.inner.queued.load(Ordering::Relaxed),
+            in_flight: self.inner.concurrency - self.inner.limiter.available_permits(),
+            queries: waits.queries,
+            total_wait: waits.total,
+            max_wait: waits.max,
+        }
+    }
+
+    /// Sends `query` to `addr` and waits for the response.
+    ///
+    /// Waits in line if too many queries are in flight already. Fails on
+    /// timeout, and with an [`ErrorMessage`] if the node answered with an
+    /// error.
+    pub async fn query(&self, addr: SocketAddr, query: Query<I>) -> Result<Response<I>> {
+        let addr = normalize(addr);
+        let permit = self.acquire().await?;
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut transactions = self.inner.transactions.lock().unwrap();
+            ensure!(
+                transactions.pending.len() <= u16::MAX as usize,
+                "too many pending queries"
+            );
+            let mut rng = rand::thread_rng();
+            let mut id = rng.gen();
+            while transactions.pending.contains_key(&id) {
+                id = rng.gen();
+            }
+            transactions.pending.insert(id, Pending { addr, sender });
+            id
+        };
+        let guard = TransactionGuard {
+            inner: &self.inner,
+            id,
+        };
+
+        let message = Message {
+            transaction_id: id.to_be_bytes().to_vec(),
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            body: Body::Query(query),
+        };
+        self.inner.socket.send_to(&message.encode(), addr).await?;
+
+        match tokio::time::timeout(self.inner.timeout, receiver).await {
+            Ok(Ok(Ok(response))) => Ok(response),
+            Ok(Ok(Err(error))) => Err(error.into()),
+            Ok(Err(_)) => bail!("receive loop stopped"),
+            Err(_) => {
+                // free the slot first, other queries don't wait for the actor
+                drop(guard);
+                drop(permit);
+                // unlike incoming packets, a timeout is not dropped when the
+                // actor is behind: the contact would never be marked failed
+                self.inner
+                    .events
+                    .send(Event::Timeout { to: addr })
+                    .await
+                    .ok();
+                bail!("query to {} timed out", addr)
+            }
+        }
+    }
+
+    /// Waits for a free query slot.
+    async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        let start = Instant::now();
+        self.inner.queued.fetch_add(1, Ordering::Relaxed);
+        let queued = QueuedGuard(&self.inner.queued);
+        let permit = self.inner.limiter.acquire().await?;
+        drop(queued);
+
+        let wait = start.elapsed();
+        let mut waits = self.inner.waits.lock().unwrap();
+        waits.queries += 1;
+        waits.total += wait;
+        waits.max = waits.max.max(wait);
+        Ok(permit)
+    }
+
+    /// Answers a query received as [`Event::Query`].
+    pub async fn respond(
+        &self,
+        addr: SocketAddr,
+        transaction_id: Vec<u8>,
+        response: Response<I>,
+    ) -> Result<()> {
+        self.send(addr, transaction_id, Body::Response(response))
+            .await
+    }
+
+    /// Answers a query received as [`Event::Query`] with an error.
+    pub async fn error(
+        &self,
+        addr: SocketAddr,
+        transaction_id: Vec<u8>,
+        error: ErrorMessage,
+    ) -> Result<()> {
+        self.send(addr, transaction_id, Body::Error(error)).await
+    }
+
+    async fn send(&self, addr: SocketAddr, transaction_id: Vec<u8>, body: Body<I>) -> Result<()> {
+        let message = Message {
+            transaction_id,
+            version: None,
+            requester_ip: Some(addr),
+            read_only: false,
+            body,
+        };
+        self.inner.socket.send_to(&message.encode(), addr).await?;
+        Ok(())
+    }
+}
+
+impl<I> Inner<I> {
+    /// Hands a response to the query waiting for it. Returns `false` if no
+    /// query from this address is waiting.
+    fn complete(
+        &self,
+        transaction_id: &[u8],
+        from: SocketAddr,
+        result: Result<Response<I>, ErrorMessage>,
+    ) -> bool {
+        let Ok(id) = <[u8; 2]>::try_from(transaction_id) else {
+            return false;
+        };
+        let id = u16::from_be_bytes(id);
+
+        let mut transactions = self.transactions.lock().unwrap();
+        // responses from other addresses are dropped, as they could be spoofed
+        if !matches!(transactions.pending.get(&id), Some(pending) if pending.addr == from) {
+            return false;
+        }
+        let pending = transactions.pending.remove(&id).expect("checked above");
+        pending.sender.send(result).is_ok()
+    }
+}
+
+async fn recv_loop<I: Id>(inner: Arc<Inner<I>>, events: mpsc::Sender<Event<I>>) {
+    let mut buf = vec![0u8; bencode::MAX_SIZE];
+    loop {
+        let (len, from) = match inner.socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            // errors are caused by single packets, e.g. ICMP port unreachable
+            Err(_) => continue,
+        };
+        let from = normalize(from);
+        let message = match Message::decode(&buf[..len]) {
+            Ok(message) => message,
+            Err(_) => {
+                // queries we can't handle are answered with an error, other
+                // malformed packets are dropped
+                if let Some((transaction_id, error)) = query_error(&buf[..len]) {
+                    let message = Message::<I> {
+                        transaction_id,
+                        version: None,
+                        requester_ip: Some(from),
+                        read_only: false,
+                        body: Body::Error(error),
+                    };
+                    inner.socket.send_to(&message.encode(), from).await.ok();
+                }
+                continue;
+            }
+        };
+
+        let event = match message.body {
+            Body::Query(query) => Some(Event::Query {
+                from,
+                transaction_id: message.transaction_id,
+                read_only: message.read_only,
+                query: Box::new(query),
+            }),
+            Body::Response(response) => {
+                let id = response.id;
+                inner
+                    .complete(&message.transaction_id, from, Ok(response))
+                    .then_some(Event::Response { from, id })
+            }
+            Body::Error(error) => {
+                inner.complete(&message.transaction_id, from, Err(error));
+                None
+            }
+        };
+        if let Some(event) = event {
+            // drop events instead of blocking the receive loop when overloaded
+            let _ = events.try_send(event);
+        }
+    }
+}
+
+/// The error to answer a query with that failed to decode: method unknown
+/// for a method we don't know, a protocol error for missing or invalid
+/// arguments. `None` for packets that are not queries with a transaction id.
+fn query_error(buf: &[u8]) -> Option<(Vec<u8>, ErrorMessage)> {
+    let value = bencode::decode(buf).ok()?;
+    let transaction_id = get_bytes(&value, b"t").ok()?.to_vec();
+    if get_bytes(&value, b"y").ok()? != b"q" {
+        return None;
+    }
+    let error = match get_bytes(&value, b"q") {
+        Ok(method) if !METHODS.contains(&method) => ErrorMessage {
+            code: ErrorMessage::METHOD_UNKNOWN,
+            message: "method unknown".into(),
+        },
+        _ => ErrorMessage {
+            code: ErrorMessage::PROTOCOL,
+            message: "invalid arguments".into(),
+        },
+    };
+    Some((transaction_id, error))
+}
+
+/// The methods of [`Query`].
+const METHODS: [&[u8]; 6] = [
+    b"ping",
+    b"find_node",
+    b"get_peers",
+    b"announce_peer",
+    b"get",
+    b"put",
+];
+
+/// A single KRPC message, as sent in one UDP datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message<I = [u8; 20]> {
+    /// `t`: transaction id, echoed back in responses.
+    pub transaction_id: Vec<u8>,
+    /// `v`: client version, e.g. `LT\x01\x02` for libtorrent.
+    pub version: Option<Vec<u8>>,
+    /// `ip`: the address the responder saw the query coming from (BEP 42).
+    pub requester_ip: Option<SocketAddr>,
+    /// `ro`: the sender does not answer queries and should not be added to
+    /// routing tables (BEP 43).
+    pub read_only: bool,
+    /// `y`: the kind of message, together with its `q`/`a`, `r` or `e` payload.
+    pub body: Body<I>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body<I = [u8; 20]> {
+    Query(Query<I>),
+    Response(Response<I>),
+    Error(ErrorMessage),
+}
+
+/// The queries of BEP 5 and BEP 44, with their `a` arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query<I = [u8; 20]> {
+    Ping {
+        id: I,
+    },
+    FindNode {
+        id: I,
+        target: I,
+        /// `want`: the address families to return nodes for, `n4` and `n6`
+        /// (BEP 32). Empty if not given.
+        want: Vec<Vec<u8>>,
+    },
+    GetPeers {
+        id: I,
+        info_hash: I,
+        /// See [`Query::FindNode::want`].
+        want: Vec<Vec<u8>>,
+    },
+    AnnouncePeer {
+        id: I,
+        info_hash: I,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+    Get {
+        id: I,
+        target: I,
+        seq: Option<i64>,
+    },
+    Put {
+        id: I,
+        token: Vec<u8>,
+        v: Value,
+        k: Option<[u8; 32]>,
+        sig: Option<[u8; 64]>,
+        seq: Option<i64>,
+        cas: Option<i64>,
+        salt: Option<Vec<u8>>,
+    },
+}
+
+/// The `r` dictionary of a response.
+///
+/// Responses don't name the query they answer, so all fields that any of the
+/// queries can return are optional here.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Response<I = [u8; 20]> {
+    pub id: I,
+    pub nodes: Vec<NodeInfo<I>>,
+    pub nodes6: Vec<NodeInfo<I>>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    pub v: Option<Value>,
+    pub k: Option<[u8; 32]>,
+    pub sig: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+/// The `e` list of an error message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMessage {
+    pub code: i64,
+    pub message: String,
+}
+
+impl ErrorMessage {
+    pub const PROTOCOL: i64 = 203;
+    pub const METHOD_UNKNOWN: i64 = 204;
+}
+
+/// A node id with its address, as found in the compact `nodes` and `nodes6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo<I = [u8; 20]> {
+    pub id: I,
+    pub addr: SocketAddr,
+}
+
+impl<I: Id> Message<I> {
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let value = bencode::decode(buf)?;
+        ensure!(value.as_dict().is_some(), "message is not a dictionary");
+
+        let transaction_id = get_bytes(&value, b"t")?.to_vec();
+        let version = value
+            .get(b"v")
+            .map(|v| v.as_bytes().map(<[u8]>::to_vec).context("invalid 'v'"))
+            .transpose()?;
+        let requester_ip = value
+            .get(b"ip")
+            .map(|ip| ip.as_bytes().and_then(decode_addr).context("invalid 'ip'"))
+            .transpose()?;
+        let read_only = get_optional_integer(&value, b"ro")?.unwrap_or(0) != 0;
+
+        let body = match get_bytes(&value, b"y")? {
+            b"q" => Body::Query(Query::decode(
+                get_bytes(&value, b"q")?,
+                value.get(b"a").context("missing 'a'")?,
+            )?),
+            b"r" => Body::Response(Response::decode(value.get(b"r").context("missing 'r'")?)?),
+            b"e" => Body::Error(ErrorMessage::decode(
+                value.get(b"e").context("missing 'e'")?,
+            )?),
+            y => bail!("unknown message type {:?}", String::from_utf8_lossy(y)),
+        };
+
+        Ok(Message {
+            transaction_id,
+            version,
+            requester_ip,
+            read_only,
+            body,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.dict(|d| {
+            match &self.body {
+                Body::Query(query) => {
+                    d.dict(b"a", |a| query.encode_args(a));
+                }
+                Body::Error(error) => {
+                    d.list(b"e", |e| {
+                        e.integer(error.code);
+                        e.bytes(error.message.as_bytes());
+                    });
+                }
+                Body::Response(_) => {}
+            }
+            if let Some(ip) = &self.requester_ip {
+                d.bytes(b"ip", &encode_addr(ip));
+            }
+            match &self.body {
+                Body::Query(query) => d.bytes(b"q", query.method()),
+                Body::Response(response) => d.dict(b"r", |r| response.encode(r)),
+                Body::Error(_) => {}
+            }
+            if self.read_only {
+                d.integer(b"ro", 1);
+            }
+            d.bytes(b"t", &self.transaction_id);
+            if let Some(version) = &self.version {
+                d.bytes(b"v", version);
+            }
+            let y: &[u8] = match &self.body {
+                Body::Query(_) => b"q",
+                Body::Response(_) => b"r",
+                Body::Error(_) => b"e",
+            };
+            d.bytes(b"y", y);
+        });
+        encoder.finish()
+    }
+}
+
+impl<I: Id> Query<I> {
+    /// The id of the querying node.
+    pub fn id(&self) -> &I {
+        match self {
+            Query::Ping { id }
+            | Query::FindNode { id, .. }
+            | Query::GetPeers { id, .. }
+            | Query::AnnouncePeer { id, .. }
+            | Query::Get { id, .. }
+            | Query::Put { id, .. } => id,
+        }
+    }
+
+    /// The method name, sent as `q`.
+    pub fn method(&self) -> &'static [u8] {
+        match self {
+            Query::Ping { .. } => b"ping",
+            Query::FindNode { .. } => b"find_node",
+            Query::GetPeers { .. } => b"get_peers",
+            Query::AnnouncePeer { .. } => b"announce_peer",
+            Query::Get { .. } => b"get",
+            Query::Put { .. } => b"put",
+        }
+    }
+
+    fn decode(method: &[u8], a: &Value) -> Result<Self> {
+        ensure!(a.as_dict().is_some(), "'a' is not a dictionary");
+        let id = get_id(a, b"id")?;
+
+        let query = match method {
+            b"ping" => Query::Ping { id },
+            b"find_node" => Query::FindNode {
+                id,
+                target: get_id(a, b"target")?,
+                want: get_want(a)?,
+            },
+            b"get_peers" => Query::GetPeers {
+                id,
+                info_hash: get_id(a, b"info_hash")?,
+                want: get_want(a)?,
+            },
+            b"announce_peer" => Query::AnnouncePeer {
+                id,
+                info_hash: get_id(a, b"info_hash")?,
+                port: u16::try_from(get_integer(a, b"port")?).context("invalid 'port'")?,
+                implied_port: get_optional_integer(a, b"implied_port")?.unwrap_or(0) != 0,
+                token: get_bytes(a, b"token")?.to_vec(),
+            },
+            b"get" => Query::Get {
+                id,
+                target: get_id(a, b"target")?,
+                seq: get_optional_integer(a, b"seq")?,
+            },
+            b"put" => Query::Put {
+                id,
+                token: get_bytes(a, b"token")?.to_vec(),
+                v: a.get(b"v").context("missing 'v'")?.clone(),
+                k: get_optional_array(a, b"k")?,
+                sig: get_optional_array(a, b"sig")?,
+                seq: get_optional_integer(a, b"seq")?,
+                cas: get_optional_integer(a, b"cas")?,
+                salt: get_optional_bytes(a, b"salt")?.map(<[u8]>::to_vec),
+            },
+            q => bail!("unknown query {:?}", String::from_utf8_lossy(q)),
+        };
+        Ok(query)
+    }
+
+    fn encode_args(&self, a: &mut DictEncoder<'_>) {
+        match self {
+            Query::Ping { id } => {
+                a.bytes(b"id", id.as_ref());
+            }
+            Query::FindNode { id, target, want } => {
+                a.bytes(b"id", id.as_ref());
+                a.bytes(b"target", target.as_ref());
+                encode_want(a, want);
+            }
+            Query::GetPeers {
+                id,
+                info_hash,
+                want,
+            } => {
+                a.bytes(b"id", id.as_ref());
+                a.bytes(b"info_hash", info_hash.as_ref());
+                encode_want(a, want);
+            }
+            Query::AnnouncePeer {
+                id,
+                info_hash,
+                port,
+                implied_port,
+                token,
+            } => {
+                a.bytes(b"id", id.as_ref());
+                if *implied_port {
+                    a.integer(b"implied_port", 1);
+                }
+                a.bytes(b"info_hash", info_hash.as_ref());
+                a.integer(b"port", i64::from(*port));
+                a.bytes(b"token", token);
+            }
+            Query::Get { id, target, seq } => {
+                a.bytes(b"id", id.as_ref());
+                if let Some(seq) = seq {
+                    a.integer(b"seq", *seq);
+                }
+                a.bytes(b"target", target.as_ref());
+            }
+            Query::Put {
+                id,
+                token,
+                v,
+                k,
+                sig,
+                seq,
+                cas,
+                salt,
+            } => {
+                if let Some(cas) = cas {
+                    a.integer(b"cas", *cas);
+                }
+                a.bytes(b"id", id.as_ref());
+                if let Some(k) = k {
+                    a.bytes(b"k", k);
+                }
+                if let Some(salt) = salt {
+                    a.bytes(b"salt", salt);
+                }
+                if let Some(seq) = seq {
+                    a.integer(b"seq", *seq);
+                }
+                if let Some(sig) = sig {
+                    a.bytes(b"sig", sig);
+                }
+                a.bytes(b"token", token);
+                a.value(b"v", v);
+            }
+        }
+    }
+}
+
+impl<I: Id> Response<I> {
+    fn decode(r: &Value) -> Result<Self> {
+        ensure!(r.as_dict().is_some(), "'r' is not a dictionary");
+
+        let nodes = get_optional_bytes(r, b"nodes")?
+            .map(|nodes| decode_nodes(nodes, COMPACT_ADDR_LEN))
+            .transpose()
+            .context("invalid 'nodes'")?
+            .unwrap_or_default();
+        let nodes6 = get_optional_bytes(r, b"nodes6")?
+            .map(|nodes| decode_nodes(nodes, COMPACT_ADDR6_LEN))
+            .transpose()
+            .context("invalid 'nodes6'")?
+            .unwrap_or_default();
+        let values = match r.get(b"values") {
+            Some(values) => values
+                .as_list()
+                .context("invalid 'values'")?
+                .iter()
+                .map(|peer| peer.as_bytes().and_then(decode_addr))
+                .collect::<Option<Vec<_>>>()
+                .context("invalid 'values'")?,
+            None => Vec::new(),
+        };
+
+        Ok(Response {
+            id: get_id(r, b"id")?,
+            nodes,
+            nodes6,
+            values,
+            token: get_optional_bytes(r, b"token")?.map(<[u8]>::to_vec),
+            v: r.get(b"v").cloned(),
+            k: get_optional_array(r, b"k")?,
+            sig: get_optional_array(r, b"sig")?,
+            seq: get_optional_integer(r, b"seq")?,
+        })
+    }
+
+    fn encode(&self, r: &mut DictEncoder<'_>) {
+        r.bytes(b"id", self.id.as_ref());
+        if let Some(k) = &self.k {
+            r.bytes(b"k", k);
+        }
+        if !self.nodes.is_empty() {
+            r.bytes(b"nodes", &encode_nodes(&self.nodes));
+        }
+        if !self.nodes6.is_empty() {
+            r.bytes(b"nodes6", &encode_nodes(&self.nodes6));
+        }
+        if let Some(seq) = self.seq {
+            r.integer(b"seq", seq);
+        }
+        if let Some(sig) = &self.sig {
+            r.bytes(b"sig", sig);
+        }
+        if let Some(token) = &self.token {
+            r.bytes(b"token", token);
+        }
+        if let Some(v) = &self.v {
+            r.value(b"v", v);
+        }
+        if !self.values.is_empty() {
+            r.list(b"values", |values| {
+                for peer in &self.values {
+                    values.bytes(&encode_addr(peer));
+                }
+            });
+        }
+    }
+}
+
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorMessage {}
+
+impl ErrorMessage {
+    fn decode(e: &Value) -> Result<Self> {
+        match e.as_list() {
+            Some([code, message]) => Ok(ErrorMessage {
+                code: code.as_integer().context("invalid error code")?,
+                message: String::from_utf8_lossy(
+                    message.as_bytes().context("invalid error message")?,
+                )
+                .into_owned(),
+            }),
+            _ => bail!("invalid 'e'"),
+        }
+    }
+}
+
+fn get_bytes<'a>(value: &'a Value, key: &[u8]) -> Result<&'a [u8]> {
+    get_optional_bytes(value, key)?.ok_or_else(|| missing(key))
+}
+
+fn get_optional_bytes<'a>(value: &'a Value, key: &[u8]) -> Result<Option<&'a [u8]>> {
+    value
+        .get(key)
+        .map(|v| v.as_bytes().ok_or_else(|| invalid(key)))
+        .transpose()
+}
+
+fn get_integer(value: &Value, key: &[u8]) -> Result<i64> {
+    get_optional_integer(value, key)?.ok_or_else(|| missing(key))
+}
+
+fn get_optional_integer(value: &Value, key: &[u8]) -> Result<Option<i64>> {
+    value
+        .get(key)
+        .map(|v| v.as_integer().ok_or_else(|| invalid(key)))
+        .transpose()
+}
+
+fn get_id<I: Id>(value: &Value, key: &[u8]) -> Result<I> {
+    I::from_slice(get_bytes(value, key)?).ok_or_else(|| invalid(key))
+}
+
+fn get_optional_array<const N: usize>(value: &Value, key: &[u8]) -> Result<Option<[u8; N]>> {
+    get_optional_bytes(value, key)?
+        .map(|b| b.try_into().map_err(|_| invalid(key)))
+        .transpose()
+}
+
+/// Decodes the optional `want` list of find_node and get_peers.
+fn get_want(a: &Value) -> Result<Vec<Vec<u8>>> {
+    let Some(want) = a.get(b"want") else {
+        return Ok(Vec::new());
+    };
+    want.as_list()
+        .and_then(|want| {
+            want.iter()
+                .map(|family| family.as_bytes().map(<[u8]>::to_vec))
+                .collect()
+        })
+        .ok_or_else(|| invalid(b"want"))
+}
+
+fn missing(key: &[u8]) -> anyhow::Error {
+    anyhow!("missing '{}'", String::from_utf8_lossy(key))
+}
+
+fn invalid(key: &[u8]) -> anyhow::Error {
+    anyhow!("invalid '{}'", String::from_utf8_lossy(key))
+}
+
+/// Maps IPv4-mapped IPv6 addresses, as seen on dual stack sockets, to IPv4.
+fn normalize(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Decodes a compact peer address, 6 bytes for IPv4 and 18 bytes for IPv6.
+fn decode_addr(buf: &[u8]) -> Option<SocketAddr> {
+    match buf.len() {
+        6 => {
+            let ip: [u8; 4] = buf[..4].try_into().ok()?;
+            let port = u16::from_be_bytes([buf[4], buf[5]]);
+            Some(SocketAddrV4::new(Ipv4Addr::from(ip), port).into())
+        }
+        18 => {
+            let ip: [u8; 16] = buf[..16].try_into().ok()?;
+            let port = u16::from_be_bytes([buf[16], buf[17]]);
+            Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0).into())
+        }
+        _ => None,
+    }
+}
+
+fn encode_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+/// Decodes compact node infos, each an id followed by an address of
+/// `addr_len` bytes.
+fn decode_nodes<I: Id>(buf: &[u8], addr_len: usize) -> Result<Vec<NodeInfo<I>>> {
+    let len = I::LEN + addr_len;
+    ensure!(
+        buf.len().is_multiple_of(len),
+        "invalid compact node info length"
+    );
+    buf.chunks_exact(len)
+        .map(|chunk| {
+            let (id, addr) = chunk.split_at(I::LEN);
+            Ok(NodeInfo {
+                id: I::from_slice(id).context("invalid id")?,
+                addr: decode_addr(addr).context("invalid address")?,
+            })
+        })
+        .collect()
+}
+
+fn encode_nodes<I: Id>(nodes: &[NodeInfo<I>]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * (I::LEN + COMPACT_ADDR6_LEN));
+    for node in nodes {
+        buf.extend_from_slice(node.id.as_ref());
+        buf.extend_from_slice(&encode_addr(&node.addr));
+    }
+    buf
+}
+
+fn encode_want(a: &mut DictEncoder<'_>, want: &[Vec<u8>]) {
+    if !want.is_empty() {
+        a.list(b"want", |list| {
+            for family in want {
+                list.bytes(family);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{Hash, Sha1};
+
+    type NodeId = <Sha1 as Hash>::Id;
+
+    /// Hand-written packets in the shape libtorrent uses: a `v` of `LT` plus
+    /// version, 2 byte transaction ids, the `ip` field in responses and `ro`
+    /// from read-only nodes. Not captures: ids, the token and the put example
+    /// are the placeholders of BEP 5 and BEP 44.
+    mod libtorrent_style {
+        pub const PING_QUERY: &[u8] =
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:LT\x02\x001:y1:qe";
+        pub const READ_ONLY_PING_QUERY: &[u8] =
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:ab1:v4:LT\x02\x001:y1:qe";
+        pub const PING_RESPONSE: &[u8] = b"d2:ip6:\x7f\x00\x00\x01\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:v4:LT\x02\x001:y1:re";
+        pub const ANNOUNCE_PEER_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:v4:LT\x02\x001:y1:qe";
+        pub const PUT_QUERY: &[u8] = b"d1:ad3:casi3e2:id20:abcdefghij01234567891:k32:\x77\xff\x84\x90\x5a\x91\x93\x63\x40\x4d\x0b\x2a\x4c\x4a\x3c\x77\x06\x28\x83\xb7\x27\x26\x58\x66\x21\x10\xa0\x21\x1b\xec\xa3\xd24:salt6:foobar3:seqi4e3:sig64:\x6c\x0f\x3c\x11\xa2\xad\xdd\x52\x3a\x7c\x4f\xa1\x1c\xe0\xc9\x0f\x40\xac\x37\x2f\x99\xb6\x06\x1b\x17\xca\xf3\xc2\xaa\x2d\xbe\x31\xd5\x87\x02\x7c\xa3\x64\xd5\x23\xa5\x0d\x17\x8c\x50\xd2\x30\xbc\x0d\xde\x65\xde\xd2\x92\x9d\x94\x93\x4d\x48\x73\x0e\x3b\x54\x065:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:v4:LT\x02\x001:y1:qe";
+    }
+
+    /// Hand-written packets in the shape of jech's dht library, as used by
+    /// Transmission: a `v` of `TR` plus version, 4 byte transaction ids,
+    /// 8 byte tokens and the `want` list of dual stack nodes. Not captures:
+    /// ids, addresses and the token are made up.
+    mod transmission_style {
+        pub const FIND_NODE_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t4:fn\x00\x001:v4:TR\x04\x001:y1:qe";
+        pub const FIND_NODE_RESPONSE: &[u8] = b"d1:rd2:id20:0123456789abcdefghij5:nodes52:mnopqrstuvwxyz123456\xc0\xa8\x01\x01\x1a\xe1zyxwvutsrqponm654321\x0a\x00\x00\x02\xc8\xd5e1:t4:fn\x00\x001:v4:TR\x04\x001:y1:re";
+        pub const GET_PEERS_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t4:gp\x00\x011:v4:TR\x04\x001:y1:qe";
+        pub const GET_PEERS_RESPONSE: &[u8] = b"d1:rd2:id20:abcdefghij01234567895:token8:\x01\x02\x03\x04\x05\x06\x07\x086:valuesl6:\x7f\x00\x00\x01\x1a\xe16:\x0a\x00\x00\x02\x1a\xe218:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe3ee1:t4:gp\x00\x011:v4:TR\x04\x001:y1:re";
+        pub const ERROR: &[u8] =
+            b"d1:eli203e14:Protocol Errore1:t4:gp\x00\x011:v4:TR\x04\x001:y1:ee";
+    }
+
+    fn id(s: &[u8]) -> NodeId {
+        s.try_into().unwrap()
+    }
+
+    fn roundtrip(packet: &[u8]) -> Message {
+        let message = Message::decode(packet).unwrap();
+        assert_eq!(
+            message.encode(),
+            packet,
+            "{:?}",
+            String::from_utf8_lossy(&message.encode())
+        );
+        message
+    }
+
+    #[test]
+    fn test_roundtrip_libtorrent_style() {
+        let ping = roundtrip(libtorrent_style::PING_QUERY);
+        assert_eq!(ping.transaction_id, b"aa");
+        assert_eq!(ping.version.as_deref(), Some(&b"LT\x02\x00"[..]));
+        assert!(!ping.read_only);
+        assert_eq!(
+            ping.body,
+            Body::Query(Query::Ping {
+                id: id(b"abcdefghij0123456789")
+            })
+        );
+
+        let read_only = roundtrip(libtorrent_style::READ_ONLY_PING_QUERY);
+        assert!(read_only.read_only);
+        assert_eq!(read_only.body, ping.body);
+
+        let pong = roundtrip(libtorrent_style::PING_RESPONSE);
+        assert_eq!(pong.requester_ip, Some("127.0.0.1:6881".parse().unwrap()));
+        assert_eq!(
+            pong.body,
+            Body::Response(Response {
+                id: id(b"mnopqrstuvwxyz123456"),
+                ..Default::default()
+            })
+        );
+
+        let announce = roundtrip(libtorrent_style::ANNOUNCE_PEER_QUERY);
+        assert_eq!(
+            announce.body,
+            Body::Query(Query::AnnouncePeer {
+                id: id(b"abcdefghij0123456789"),
+                info_hash: id(b"mnopqrstuvwxyz123456"),
+                port: 6881,
+                implied_port: true,
+                token: b"aoeusnth".to_vec(),
+            })
+        );
+
+        let put = roundtrip(libtorrent_style::PUT_QUERY);
+        let Body::Query(Query::Put {
+            v,
+            k,
+            sig,
+            seq,
+            cas,
+            salt,
+            ..
+        }) = put.body
+        else {
+            panic!("expected put query");
+        };
+        assert_eq!(v, Value::from(&b"Hello World!"[..]));
+        assert!(k.is_some());
+        assert!(sig.is_some());
+        assert_eq!(seq, Some(4));
+        assert_eq!(cas, Some(3));
+        assert_eq!(salt.as_deref(), Some(&b"foobar"[..]));
+    }
+
+    #[test]
+    fn test_roundtrip_transmission_style() {
+        let find_node = roundtrip(transmission_style::FIND_NODE_QUERY);
+        assert_eq!(find_node.transaction_id, b"fn\x00\x00");
+        assert_eq!(
+            find_node.body,
+            Body::Query(Query::FindNode {
+                id: id(b"abcdefghij0123456789"),
+                target: id(b"mnopqrstuvwxyz123456"),
+                want: vec![b"n4".to_vec(), b"n6".to_vec()],
+            })
+        );
+
+        let nodes = roundtrip(transmission_style::FIND_NODE_RESPONSE);
+        let Body::Response(response) = nodes.body else {
+            panic!("expected response");
+        };
+        assert_eq!(
+            response.nodes,
+            vec![
+                NodeInfo {
+                    id: id(b"mnopqrstuvwxyz123456"),
+                    addr: "192.168.1.1:6881".parse().unwrap(),
+                },
+                NodeInfo {
+                    id: id(b"zyxwvutsrqponm654321"),
+                    addr: "10.0.0.2:51413".parse().unwrap(),
+                },
+            ]
+        );
+
+        let get_peers = roundtrip(transmission_style::GET_PEERS_QUERY);
+        assert_eq!(get_peers.body.clone(), {
+            Body::Query(Query::GetPeers {
+                id: id(b"abcdefghij0123456789"),
+                info_hash: id(b"mnopqrstuvwxyz123456"),
+                want: vec![],
+            })
+        });
+
+        let peers = roundtrip(transmission_style::GET_PEERS_RESPONSE);
+        let Body::Response(response) = peers.body else {
+            panic!("expected response");
+        };
+        assert_eq!(
+            response.token.as_deref(),
+            Some(&[1, 2, 3, 4, 5, 6, 7, 8][..])
+        );
+        assert_eq!(
+            response.values,
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+                "[2001:db8::1]:6883".parse().unwrap(),
+            ]
+        );
+
+        let error = roundtrip(transmission_style::ERROR);
+        assert_eq!(
+            error.body,
+            Body::Error(ErrorMessage {
+                code: ErrorMessage::PROTOCOL,
+                message: "Protocol Error".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_get() {
+        let message = Message {
+            transaction_id: b"aa".to_vec(),
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            body: Body::Query(Query::Get {
+                id: id(b"abcdefghij0123456789"),
+                target: id(b"mnopqrstuvwxyz123456"),
+                seq: Some(1),
+            }),
+        };
+        let buf = message.encode();
+        assert_eq!(
+            &buf[..],
+            &b"d1:ad2:id20:abcdefghij01234567893:seqi1e6:target20:mnopqrstuvwxyz123456e1:q3:get1:t2:aa1:y1:qe"[..]
+        );
+        assert_eq!(Message::decode(&buf).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let error_code = |packet: &[u8]| query_error(packet).map(|(_, error)| error.code);
+
+        // missing transaction id, can't be answered
+        let packet = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:y1:qe";
+        assert!(<Message>::decode(packet).is_err());
+        assert_eq!(error_code(packet), None);
+        // short node id
+        let packet = b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe";
+        assert!(<Message>::decode(packet).is_err());
+        assert_eq!(error_code(packet), Some(ErrorMessage::PROTOCOL));
+        // unknown query, e.g. sample_infohashes of BEP 51
+        let packet = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+        assert!(<Message>::decode(packet).is_err());
+        assert_eq!(
+            query_error(packet),
+            Some((
+                b"aa".to_vec(),
+                ErrorMessage {
+                    code: ErrorMessage::METHOD_UNKNOWN,
+                    message: "method unknown".into(),
+                }
+            ))
+        );
+        // truncated compact node info, a response is never answered
+        let packet = b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re";
+        assert!(<Message>::decode(packet).is_err());
+        assert_eq!(error_code(packet), None);
+        // port out of range
+        let packet = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti65536e5:token1:xe1:q13:announce_peer1:t2:aa1:y1:qe";
+        assert!(<Message>::decode(packet).is_err());
+        assert_eq!(error_code(packet), Some(ErrorMessage::PROTOCOL));
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_query_is_answered() {
+        let (a, _a_events) = bind(Duration::from_millis(200)).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+        client
+            .send_to(packet, a.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (len, _) = client.recv_from(&mut buf).await.unwrap();
+        let message = <Message>::decode(&buf[..len]).unwrap();
+        assert_eq!(message.transaction_id, b"aa");
+        let Body::Error(error) = message.body else {
+            panic!("expected error");
+        };
+        assert_eq!(error.code, ErrorMessage::METHOD_UNKNOWN);
+    }
+
+    async fn bind(timeout: Duration) -> (Rpc, mpsc::Receiver<Event>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Rpc::new(socket, timeout, 16)
+    }
+
+    /// Answers every ping with `id`, and find_node with the target as id.
+    async fn answer(rpc: Rpc, mut events: mpsc::Receiver<Event>, id: NodeId) {
+        while let Some(event) = events.recv().await {
+            if let Event::Query {
+                from,
+                transaction_id,
+                query,
+                ..
+            } = event
+            {
+                let response = match *query {
+                    Query::FindNode { target, .. } => Response {
+                        id: target,
+                        ..Default::default()
+                    },
+                    _ => Response {
+                        id,
+                        ..Default::default()
+                    },
+                };
+                rpc.respond(from, transaction_id, response).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_response() {
+        let (a, mut a_events) = bind(Duration::from_secs(2)).await;
+        let (b, b_events) = bind(Duration::from_secs(2)).await;
+        let b_addr = b.local_addr().unwrap();
+        tokio::task::spawn(answer(b, b_events, [2; NodeId::LEN]));
+
+        let response = a
+            .query(
+                b_addr,
+                Query::Ping {
+                    id: [1; NodeId::LEN],
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.id, [2; NodeId::LEN]);
+
+        match a_events.recv().await.unwrap() {
+            Event::Response { from, id } => {
+                assert_eq!(from, b_addr);
+                assert_eq!(id, [2; NodeId::LEN]);
+            }
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries_are_matched() {
+        let (a, _a_events) = bind(Duration::from_secs(2)).await;
+        let (b, b_events) = bind(Duration::from_secs(2)).await;
+        let b_addr = b.local_addr().unwrap();
+        tokio::task::spawn(answer(b, b_events, [0; NodeId::LEN]));
+
+        let queries = (0..32u8).map(|i| {
+            let a = a.clone();
+            async move {
+                let query = Query::FindNode {
+                    id: [0; NodeId::LEN],
+                    target: [i; NodeId::LEN],
+                    want: vec![],
+                };
+                (i, a.query(b_addr, query).await.unwrap())
+            }
+        });
+        let handles: Vec<_> = queries.map(tokio::task::spawn).collect();
+        for handle in handles {
+            let (i, response) = handle.await.unwrap();
+            assert_eq!(response.id, [i; NodeId::LEN]);
+        }
+        assert!(a.inner.transactions.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let (a, mut a_events) = bind(Duration::from_millis(50)).await;
+        // bound, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let err = a
+            .query(
+                silent.local_addr().unwrap(),
+                Query::Ping {
+                    id: [1; NodeId::LEN],
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        assert!(a.inner.transactions.lock().unwrap().pending.is_empty());
+        match a_events.recv().await.unwrap() {
+            Event::Timeout { to } => assert_eq!(to, silent.local_addr().unwrap()),
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_response() {
+        let (a, _a_events) = bind(Duration::from_secs(2)).await;
+        let (b, mut b_events) = bind(Duration::from_secs(2)).await;
+        let b_addr = b.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            while let Some(Event::Query {
+                from,
+                transaction_id,
+                ..
+            }) = b_events.recv().await
+            {
+                let error = ErrorMessage {
+                    code: ErrorMessage::METHOD_UNKNOWN,
+                    message: "Method Unknown".into(),
+                };
+                b.error(from, transaction_id, error).await.unwrap();
+            }
+        });
+
+        let err = a
+            .query(
+                b_addr,
+                Query::Ping {
+                    id: [1; NodeId::LEN],
+                },
+            )
+            .await
+            .unwrap_err();
+        let error = err.downcast::<ErrorMessage>().unwrap();
+        assert_eq!(error.code, ErrorMessage::METHOD_UNKNOWN);
+    }
+
+    #[tokio::test]
+    async fn test_response_from_other_address_is_ignored() {
+        let (a, _a_events) = bind(Duration::from_millis(200)).await;
+        let a_addr = a.local_addr().unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let query = tokio::task::spawn({
+            let a = a.clone();
+            let target = target.local_addr().unwrap();
+            async move {
+                a.query(
+                    target,
+                    Query::Ping {
+                        id: [1; NodeId::LEN],
+                    },
+                )
+                .await
+            }
+        });
+
+        let mut buf = [0u8; 1500];
+        let (len, _) = target.recv_from(&mut buf).await.unwrap();
+        let query_message = <Message>::decode(&buf[..len]).unwrap();
+        let response = Message {
+            transaction_id: query_message.transaction_id,
+            version: None,
+            requester_ip: None,
+            read_only: false,
+            body: Body::Response(Response {
+                id: [3; NodeId::LEN],
+                ..Default::default()
+            }),
+        };
+        spoofer.send_to(&response.encode(), a_addr).await.unwrap();
+
+        assert!(query.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_ids_are_random() {
+        let (a, _a_events) = bind(Duration::from_millis(100)).await;
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        for _ in 0..8 {
+            let a = a.clone();
+            tokio::task::spawn(async move {
+                let query = Query::Ping {
+                    id: [1; NodeId::LEN],
+                };
+                a.query(silent_addr, query).await.ok();
+            });
+        }
+
+        let mut ids = Vec::new();
+        let mut buf = [0u8; 1500];
+        for _ in 0..8 {
+            let (len, _) = silent.recv_from(&mut buf).await.unwrap();
+            let message = <Message>::decode(&buf[..len]).unwrap();
+            let id: [u8; 2] = message.transaction_id.try_into().unwrap();
+            ids.push(u16::from_be_bytes(id));
+        }
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+        // not a counter, which an off-path attacker could predict
+        assert!(ids.windows(2).any(|pair| pair[1] - pair[0] > 1));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_frees_slot_while_actor_is_behind() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a, _a_events) = Rpc::<NodeId>::new(socket, Duration::from_millis(50), 1);
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        // nobody reads the events
+        while a
+            .inner
+            .events
+            .try_send(Event::Timeout { to: silent_addr })
+            .is_ok()
+        {}
+
+        let query = tokio::task::spawn({
+            let a = a.clone();
+            async move {
+                let query = Query::Ping {
+                    id: [1; NodeId::LEN],
+                };
+                a.query(silent_addr, query).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // still waiting to report the timeout, but no longer in flight
+        assert!(!query.is_finished());
+        assert_eq!(a.stats().in_flight, 0);
+        assert!(a.inner.transactions.lock().unwrap().pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a, _a_events) = Rpc::new(socket, Duration::from_millis(100), 2);
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let handles: Vec<_> = (0..5)
+            .map(|_| {
+                let a = a.clone();
+                tokio::task::spawn(async move {
+                    a.query(
+                        silent_addr,
+                        Query::Ping {
+                            id: [1; NodeId::LEN],
+                        },
+                    )
+                    .await
+                })
+            })
+            .collect();
+
+        // only two queries are sent before the first ones time out
+        let mut buf = [0u8; 1500];
+        for _ in 0..2 {
+            silent.recv_from(&mut buf).await.unwrap();
+        }
+        let stats = a.stats();
+        assert_eq!(stats.in_flight, 2);
+        assert_eq!(stats.queued, 3);
+        assert_eq!(stats.queries, 2);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), silent.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        for handle in handles {
+            assert!(handle.await.unwrap().is_err());
+        }
+        let stats = a.stats();
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.queries, 5);
+        // the last two queries waited for two timeouts
+        assert!(stats.max_wait >= Duration::from_millis(200));
+        assert!(stats.average_wait() < stats.max_wait);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_query_leaves_queue() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a, _a_events) = Rpc::new(socket, Duration::from_secs(5), 1);
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let first = tokio::task::spawn({
+            let a = a.clone();
+            async move {
+                a.query(
+                    silent_addr,
+                    Query::Ping {
+                        id: [1; NodeId::LEN],
+                    },
+                )
+                .await
+            }
+        });
+        let mut buf = [0u8; 1500];
+        silent.recv_from(&mut buf).await.unwrap();
+
+        let second = tokio::time::timeout(
+            Duration::from_millis(20),
+            a.query(
+                silent_addr,
+                Query::Ping {
+                    id: [1; NodeId::LEN],
+                },
+            ),
+        )
+        .await;
+        assert!(second.is_err());
+        assert_eq!(a.stats().queued, 0);
+        assert_eq!(a.stats().in_flight, 1);
 
-impl Rpc {
-    pub fn new() -> Self {
-        Rpc {}
+        first.abort();
+        let _ = first.await;
+        assert_eq!(a.stats().in_flight, 0);
     }
 }
@@ -1,39 +1,230 @@
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use lru::LruCache;
-use url::Host;
 
 use crate::{
-    kbucket::{self, Kbucket},
-    HASH_LENGTH,
+    hash::Id,
+    kbucket::{self, Arbiter, Contact as _, Kbucket, Status},
+    rpc::NodeInfo,
 };
 
 // new LRU({ maxAge: ROTATE_INTERVAL, max: opts.maxTables || 1000 })
 
-type Key = [u8; HASH_LENGTH];
+/// Nodes stay good for 15 minutes after they were last active (BEP5).
+const GOOD_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Nodes that failed to respond to this many queries in a row are bad.
+const MAX_FAILURES: u32 = 2;
+
+/// Nodes close to a lookup target, with the tokens they gave us.
+pub type Table<I = [u8; 20]> = Kbucket<I, Contact<I>>;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Contact {
-    id: [u8; 20],
-    host: Host,
-    port: u16,
-    token: Vec<u8>,
+pub struct Contact<I = [u8; 20]> {
+    id: I,
+    addr: SocketAddr,
+    /// Token from the node's get_peers response, needed to announce to it.
+    token: Option<Vec<u8>>,
+    last_response: Option<Instant>,
+    last_query: Option<Instant>,
+    /// Queries in a row the node did not respond to.
+    failures: u32,
 }
 
-impl kbucket::Contact for Contact {
-    type Id = [u8; 20];
+impl<I: Id> Contact<I> {
+    pub fn new(id: I, addr: SocketAddr) -> Self {
+        Contact {
+            id,
+            addr,
+            token: None,
+            last_response: None,
+            last_query: None,
+            failures: 0,
+        }
+    }
+
+    /// The node responded to one of our queries.
+    pub fn responded(&mut self) {
+        self.last_response = Some(Instant::now());
+        self.failures = 0;
+    }
+
+    /// The node sent us a query.
+    pub fn queried(&mut self) {
+        self.last_query = Some(Instant::now());
+    }
+
+    /// The node did not respond to one of our queries.
+    pub fn failed(&mut self) {
+        self.failures += 1;
+    }
 
-    fn id(&self) -> &[u8; 20] {
+    pub fn with_token(mut self, token: Vec<u8>) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn token(&self) -> Option<&[u8]> {
+        self.token.as_deref()
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn node_info(&self) -> NodeInfo<I> {
+        NodeInfo {
+            id: self.id,
+            addr: self.addr,
+        }
+    }
+}
+
+impl<I: Id> kbucket::Contact for Contact<I> {
+    type Id = I;
+
+    fn id(&self) -> &I {
         &self.id
     }
+
+    /// Good if the node responded to one of our queries within the last 15
+    /// minutes, or has responded before and sent us a query within the last
+    /// 15 minutes. Bad if it failed to respond to multiple queries in a row.
+    /// Questionable otherwise, also right after a single failure.
+    fn status(&self) -> Status {
+        if self.failures >= MAX_FAILURES {
+            return Status::Bad;
+        }
+        if self.failures > 0 {
+            return Status::Questionable;
+        }
+        let recent = |time: Option<Instant>| time.is_some_and(|t| t.elapsed() < GOOD_INTERVAL);
+        if recent(self.last_response) || (self.last_response.is_some() && recent(self.last_query)) {
+            Status::Good
+        } else {
+            Status::Questionable
+        }
+    }
+}
+
+/// Keeps the address of a known node until the node goes bad.
+///
+/// A node that shows up with a known id from another address is ignored, as
+/// anyone can claim an id. Only once the node at the known address stopped
+/// responding is it replaced.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StableAddr;
+
+impl<I: Id> Arbiter<Contact<I>> for StableAddr {
+    fn should_replace(&self, incumbent: &Contact<I>, candidate: &Contact<I>) -> bool {
+        candidate.addr == incumbent.addr || incumbent.status() == Status::Bad
+    }
+}
+
+/// Recent lookup tables by target. Tables are dropped after `max_age`, when
+/// their tokens are no longer valid.
+pub struct Tables<I: Id = [u8; 20]> {
+    max_age: Duration,
+    tables: LruCache<I, (Instant, Table<I>)>,
 }
 
-pub struct Tables(LruCache<Key, Kbucket<[u8; 20], Contact>>);
-impl Tables {
+impl<I: Id> Tables<I> {
     pub fn new(max_age: Duration, max: usize) -> Result<Self> {
-        // TODO: figure out max_age
+        Ok(Tables {
+            max_age,
+            tables: LruCache::new(max.try_into()?),
+        })
+    }
+
+    pub fn insert(&mut self, key: I, table: Table<I>) {
+        self.tables.put(key, (Instant::now(), table));
+    }
+
+    /// Returns the table for `key`, unless it is older than `max_age`.
+    pub fn get(&mut self, key: &I) -> Option<&Table<I>> {
+        let expired = match self.tables.peek(key) {
+            Some((created, _)) => created.elapsed() > self.max_age,
+            None => return None,
+        };
+        if expired {
+            self.tables.pop(key);
+            return None;
+        }
+        self.tables.get(key).map(|(_, table)| table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact_status() {
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let long_ago = Instant::now().checked_sub(GOOD_INTERVAL * 2).unwrap();
+
+        let mut contact = Contact::new([1; 20], addr);
+        assert_eq!(contact.status(), Status::Questionable);
+        // queries alone don't make a node good
+        contact.queried();
+        assert_eq!(contact.status(), Status::Questionable);
+        contact.responded();
+        assert_eq!(contact.status(), Status::Good);
+
+        // good while it keeps querying us
+        contact.last_response = Some(long_ago);
+        assert_eq!(contact.status(), Status::Good);
+        contact.last_query = Some(long_ago);
+        assert_eq!(contact.status(), Status::Questionable);
+
+        contact.failed();
+        assert_eq!(contact.status(), Status::Questionable);
+        contact.failed();
+        assert_eq!(contact.status(), Status::Bad);
+        contact.responded();
+        assert_eq!(contact.status(), Status::Good);
+
+        // no longer good after a single failure
+        contact.failed();
+        assert_eq!(contact.status(), Status::Questionable);
+    }
+
+    #[test]
+    fn test_stable_addr() {
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let other = "127.0.0.2:6881".parse().unwrap();
+        let mut table = Kbucket::with_arbiter([0; 20], None, None, StableAddr);
+        table.add(Contact::new([1; 20], addr));
+
+        table.add(Contact::new([1; 20], other));
+        assert_eq!(table.get([1; 20]).unwrap().addr(), addr);
+        table.add(Contact::new([1; 20], addr).with_token(b"token".to_vec()));
+        assert_eq!(table.get([1; 20]).unwrap().token(), Some(&b"token"[..]));
+
+        // replaced once the node at the known address is bad
+        table.get_mut([1; 20]).unwrap().failed();
+        table.add(Contact::new([1; 20], other));
+        assert_eq!(table.get([1; 20]).unwrap().addr(), addr);
+        table.get_mut([1; 20]).unwrap().failed();
+        table.add(Contact::new([1; 20], other));
+        assert_eq!(table.get([1; 20]).unwrap().addr(), other);
+    }
+
+    #[test]
+    fn test_tables_expire() {
+        let mut tables = Tables::new(Duration::from_millis(20), 2).unwrap();
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let mut table = Table::new([1; 20], None, None);
+        table.add(Contact::new([2; 20], addr).with_token(b"token".to_vec()));
+        tables.insert([1; 20], table);
+
+        let table = tables.get(&[1; 20]).unwrap();
+        let contact = table.get([2; 20]).unwrap();
+        assert_eq!(contact.token(), Some(&b"token"[..]));
 
-        Ok(Tables(LruCache::new(max.try_into()?)))
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(tables.get(&[1; 20]).is_none());
     }
 }
@@ -1,19 +1,23 @@
 use anyhow::Result;
 use lru::LruCache;
 
-use crate::HASH_LENGTH;
+use crate::hash::Id;
 
-type Key = [u8; HASH_LENGTH];
+/// Items stored with put (BEP44), by target.
+///
+/// Nothing is stored or read yet: get and put are answered with "method
+/// unknown" until they are implemented.
+#[allow(dead_code)]
+pub struct Values<I = [u8; 20]>(LruCache<I, Value<I>>);
 
-pub struct Values(LruCache<Key, Value>);
-
-pub struct Value {
-    id: [u8; 20],
+#[allow(dead_code)]
+pub struct Value<I> {
+    id: I,
     token: Vec<u8>,
     v: Vec<u8>,
 }
 
-impl Values {
+impl<I: Id> Values<I> {
     pub fn new(max: usize) -> Result<Self> {
         Ok(Values(LruCache::new(max.try_into()?)))
     }